@@ -0,0 +1,521 @@
+// SPDX-License-Identifier: MIT
+#![cfg_attr(
+    any(feature = "vendored", not(feature = "libublksrv")),
+    allow(dead_code)
+)]
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+/// A native library we link against, either found through pkg-config or
+/// through the `<PREFIX>_LIB_DIR` override.
+struct NativeLib {
+    /// pkg-config package name
+    pkg_name: &'static str,
+    /// name passed to the linker (`-l<link_name>`)
+    link_name: &'static str,
+    /// prefix of the environment variables used to override the lookup
+    env_prefix: &'static str,
+    min_version: &'static str,
+}
+
+const LIBUBLKSRV: NativeLib = NativeLib {
+    pkg_name: "ublksrv",
+    link_name: "ublksrv",
+    env_prefix: "UBLKSRV",
+    min_version: "0.0",
+};
+
+const LIBURING: NativeLib = NativeLib {
+    pkg_name: "liburing",
+    link_name: "uring",
+    env_prefix: "URING",
+    min_version: "2.2",
+};
+
+fn env_var(name: &str) -> Option<String> {
+    println!("cargo:rerun-if-env-changed={name}");
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// A library found by `link()`
+struct Linked {
+    include_paths: Vec<PathBuf>,
+    /// version reported by pkg-config, or by `<PREFIX>_VERSION`
+    version: Option<String>,
+}
+
+/// Links `lib` and returns its include paths and version.
+fn link(lib: &NativeLib) -> Linked {
+    let lib_dir = env_var(&format!("{}_LIB_DIR", lib.env_prefix));
+    let include_dir = env_var(&format!("{}_INCLUDE_DIR", lib.env_prefix));
+    let statik = env_var(&format!("{}_STATIC", lib.env_prefix)).is_some();
+
+    if let Some(lib_dir) = lib_dir {
+        let kind = if statik { "static" } else { "dylib" };
+        println!("cargo:rustc-link-search=native={lib_dir}");
+        println!("cargo:rustc-link-lib={kind}={}", lib.link_name);
+        return Linked {
+            include_paths: include_dir.into_iter().map(PathBuf::from).collect(),
+            version: env_var(&format!("{}_VERSION", lib.env_prefix)),
+        };
+    }
+
+    match pkg_config::Config::new()
+        .atleast_version(lib.min_version)
+        .statik(statik)
+        .probe(lib.pkg_name)
+    {
+        Ok(found) => {
+            let mut include_paths = found.include_paths;
+            include_paths.extend(include_dir.map(PathBuf::from));
+            Linked {
+                include_paths,
+                version: Some(found.version),
+            }
+        }
+        Err(err) => {
+            eprintln!(
+                "ublk-sys: unable to find lib{name} (>= {version}) with pkg-config:\n\n{err}\n\n\
+                 Install the lib{name} development package, point PKG_CONFIG_PATH at its \
+                 `{pkg}.pc`, or set {prefix}_LIB_DIR (and optionally {prefix}_INCLUDE_DIR \
+                 and {prefix}_STATIC) to use a library outside of pkg-config.",
+                name = lib.link_name,
+                version = lib.min_version,
+                pkg = lib.pkg_name,
+                prefix = lib.env_prefix,
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Parses the `major.minor` part of a version string
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()
+        .and_then(|m| m.parse().ok())
+        .unwrap_or_default();
+    Some((major, minor))
+}
+
+/// Selects the layout of the `io_uring` struct, which libublksrv embeds by
+/// value in its own structs.
+///
+/// liburing 2.3 replaced part of the `io_uring_sq`/`io_uring_cq` padding with
+/// `ring_mask` and `ring_entries`, that layout is used when the `liburing-2-3`
+/// feature is enabled or the linked liburing is at least 2.3.
+fn select_liburing_layout(version: Option<&str>) {
+    println!("cargo:rustc-check-cfg=cfg(liburing_2_3)");
+
+    let detected = version.and_then(parse_version);
+    if cfg!(feature = "liburing-2-3") || detected.is_some_and(|v| v >= (2, 3)) {
+        println!("cargo:rustc-cfg=liburing_2_3");
+        // Exported to dependents as DEP_UBLKSRV_LIBURING_2_3
+        println!("cargo:liburing_2_3=1");
+    }
+}
+
+/// Builds the bundled libublksrv (and liburing, with `vendored-liburing`)
+/// sources and links them statically.
+#[cfg(feature = "vendored")]
+mod vendored {
+    use super::env_var;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    const UBLKSRV_C_SOURCES: &[&str] = &[
+        "lib/ublksrv.c",
+        "lib/ublksrv_cmd.c",
+        "lib/ublksrv_aio.c",
+        "lib/utils.c",
+    ];
+    const UBLKSRV_CXX_SOURCES: &[&str] = &["lib/ublksrv_json.cpp"];
+
+    #[cfg(feature = "vendored-liburing")]
+    const LIBURING_SOURCES: &[&str] = &[
+        "src/setup.c",
+        "src/queue.c",
+        "src/register.c",
+        "src/syscall.c",
+    ];
+
+    /// Returns the source tree of a bundled library, either from the
+    /// `<PREFIX>_SRC_DIR` override or from `vendor/<dir>`.
+    fn src_dir(env_prefix: &str, dir: &str, probe: &str) -> PathBuf {
+        let path = env_var(&format!("{env_prefix}_SRC_DIR"))
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                Path::new(env!("CARGO_MANIFEST_DIR"))
+                    .join("vendor")
+                    .join(dir)
+            });
+
+        if !path.join(probe).exists() {
+            eprintln!(
+                "ublk-sys: the `vendored` feature needs the {dir} sources in `{}`.\n\n\
+                 Run `vendor/fetch.sh` to fetch them, copy a checkout of the sources \
+                 there, or set {env_prefix}_SRC_DIR to its location.",
+                path.display()
+            );
+            process::exit(1);
+        }
+        println!("cargo:rerun-if-changed={}", path.display());
+        path
+    }
+
+    fn out_dir() -> PathBuf {
+        PathBuf::from(std::env::var("OUT_DIR").unwrap())
+    }
+
+    /// Builds liburing and returns its include path.
+    #[cfg(feature = "vendored-liburing")]
+    fn build_liburing() -> PathBuf {
+        let src = src_dir("URING", "liburing", "src/include/liburing.h");
+
+        // The version is only recorded in the rpm spec file
+        let version = fs::read_to_string(src.join("liburing.spec"))
+            .ok()
+            .and_then(|spec| {
+                spec.lines()
+                    .find_map(|l| l.strip_prefix("Version:"))
+                    .and_then(super::parse_version)
+            })
+            .unwrap_or((2, 2));
+        super::select_liburing_layout(Some(&format!("{}.{}", version.0, version.1)));
+
+        // `configure` generates these two headers, the kernel uapi headers
+        // of any supported build host provide everything compat.h covers.
+        let gen_dir = out_dir().join("liburing-include");
+        fs::create_dir_all(gen_dir.join("liburing")).unwrap();
+        fs::write(
+            gen_dir.join("liburing/compat.h"),
+            "#ifndef LIBURING_COMPAT_H\n#define LIBURING_COMPAT_H\n\
+             #include <linux/time_types.h>\n#include <linux/openat2.h>\n#endif\n",
+        )
+        .unwrap();
+        fs::write(
+            gen_dir.join("liburing/io_uring_version.h"),
+            format!(
+                "#ifndef LIBURING_VERSION_H\n#define LIBURING_VERSION_H\n\
+                 #define IO_URING_VERSION_MAJOR {}\n#define IO_URING_VERSION_MINOR {}\n#endif\n",
+                version.0, version.1
+            ),
+        )
+        .unwrap();
+
+        let include = src.join("src/include");
+        cc::Build::new()
+            .files(LIBURING_SOURCES.iter().map(|f| src.join(f)))
+            .include(&gen_dir)
+            .include(&include)
+            .define("_GNU_SOURCE", None)
+            .define("_LARGEFILE_SOURCE", None)
+            .define("_FILE_OFFSET_BITS", "64")
+            .warnings(false)
+            .compile("uring");
+
+        // compat.h lives in the generated directory, dependents need both
+        fs::copy(include.join("liburing.h"), gen_dir.join("liburing.h")).unwrap();
+        fs::copy(
+            include.join("liburing/io_uring.h"),
+            gen_dir.join("liburing/io_uring.h"),
+        )
+        .unwrap();
+        gen_dir
+    }
+
+    /// Builds libublksrv, and liburing if requested, returning their
+    /// include paths.
+    pub fn build() -> Vec<PathBuf> {
+        let mut include_paths = Vec::new();
+
+        #[cfg(feature = "vendored-liburing")]
+        include_paths.push(build_liburing());
+        #[cfg(not(feature = "vendored-liburing"))]
+        {
+            let uring = super::link(&super::LIBURING);
+            super::select_liburing_layout(uring.version.as_deref());
+            include_paths.extend(uring.include_paths);
+        }
+
+        let src = src_dir("UBLKSRV", "ubdsrv", "include/ublksrv.h");
+        let include = src.join("include");
+
+        // Normally generated by `configure`, the library sources
+        // don't rely on any of its checks.
+        let gen_dir = out_dir().join("ublksrv-include");
+        fs::create_dir_all(&gen_dir).unwrap();
+        fs::write(gen_dir.join("config.h"), "").unwrap();
+
+        let mut c = cc::Build::new();
+        c.files(UBLKSRV_C_SOURCES.iter().map(|f| src.join(f)))
+            .include(&gen_dir)
+            .include(&include)
+            .includes(&include_paths)
+            .define("_GNU_SOURCE", None)
+            .warnings(false);
+
+        let mut cxx = cc::Build::new();
+        cxx.cpp(true)
+            .files(UBLKSRV_CXX_SOURCES.iter().map(|f| src.join(f)))
+            .include(&gen_dir)
+            .include(&include)
+            .includes(&include_paths)
+            .flag_if_supported("-std=c++17")
+            .define("_GNU_SOURCE", None)
+            .warnings(false);
+
+        // Both objects go in one archive, so the json code can be
+        // resolved from the C side regardless of link order.
+        let objects = cxx.compile_intermediates();
+        c.objects(objects).compile("ublksrv");
+        // cc only links the C++ runtime for `cpp(true)` builds
+        println!("cargo:rustc-link-lib=stdc++");
+        println!("cargo:rustc-link-lib=pthread");
+
+        include_paths.push(include);
+        include_paths
+    }
+}
+
+/// Regenerates the raw layer of `cmd`, `srv`, `aio` and `iouring` from the
+/// headers, each module gets its own file in OUT_DIR.
+#[cfg(feature = "bindgen")]
+mod bindings {
+    use std::path::{Path, PathBuf};
+
+    struct Header {
+        name: &'static str,
+        /// regex of the files whose items belong to this module,
+        /// everything else is imported from the other modules
+        files: &'static [&'static str],
+        out: &'static str,
+        /// anonymous unions given the names of the hand-written layer
+        unions: &'static [Union],
+        /// module of the items declared by hand for both layers, which are
+        /// left out
+        ext: Option<&'static str>,
+    }
+
+    /// `owner.__bindgen_anon_N` of type `owner__bindgen_ty_N` becomes
+    /// `owner.field` of type `ty`
+    struct Union {
+        owner: &'static str,
+        n: u32,
+        field: &'static str,
+        ty: &'static str,
+    }
+
+    const fn sqe_union(n: u32, field: &'static str, ty: &'static str) -> Union {
+        Union {
+            owner: "io_uring_sqe",
+            n,
+            field,
+            ty,
+        }
+    }
+
+    const HEADERS: &[Header] = &[
+        Header {
+            name: "ublk_cmd.h",
+            files: &[".*/ublk_cmd\\.h"],
+            out: "ublk_cmd.rs",
+            unions: &[],
+            ext: Some("src/cmd/ext.rs"),
+        },
+        Header {
+            name: "ublksrv.h",
+            files: &[".*/ublksrv\\.h"],
+            out: "ublksrv.rs",
+            unions: &[Union {
+                owner: "ublk_io",
+                n: 1,
+                field: "union",
+                ty: "ublk_io_anon_union_ty",
+            }],
+            ext: None,
+        },
+        Header {
+            name: "ublksrv_aio.h",
+            files: &[".*/ublksrv_aio\\.h"],
+            out: "ublksrv_aio.rs",
+            unions: &[Union {
+                owner: "ublksrv_aio",
+                n: 1,
+                field: "union",
+                ty: "ublksrv_aio_union_ty",
+            }],
+            ext: None,
+        },
+        Header {
+            name: "liburing.h",
+            files: &[".*/liburing\\.h", ".*/liburing/io_uring\\.h"],
+            out: "liburing.rs",
+            unions: &[
+                sqe_union(1, "u1", "io_uring_sqe_union_1_ty"),
+                sqe_union(2, "u2", "io_uring_sqe_union_2_ty"),
+                sqe_union(3, "u3", "io_uring_sqe_union_3_ty"),
+                sqe_union(4, "u4", "io_uring_sqe_union_4_ty"),
+                sqe_union(5, "u5", "io_uring_sqe_union_5_ty"),
+            ],
+            ext: None,
+        },
+    ];
+
+    fn generate(header: &Header, include_paths: &[PathBuf], out_dir: &Path) {
+        let mut builder = bindgen::Builder::default()
+            .header_contents("wrapper.h", &format!("#include <{}>\n", header.name))
+            .clang_args(include_paths.iter().map(|p| format!("-I{}", p.display())))
+            .clang_arg("-D_GNU_SOURCE")
+            .allowlist_recursively(false)
+            .derive_default(true)
+            .derive_debug(true)
+            .layout_tests(false)
+            .generate_comments(true)
+            .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()));
+
+        for file in header.files {
+            builder = builder.allowlist_file(file);
+        }
+        if let Some(ext) = header.ext {
+            println!("cargo:rerun-if-changed={ext}");
+            for item in ext_items(ext) {
+                builder = builder.blocklist_item(item);
+            }
+        }
+
+        // liburing's functions drag in most of the libc types, the crate
+        // only needs its structures and constants
+        if header.name == "liburing.h" {
+            builder = builder.blocklist_function(".*");
+        }
+
+        let mut code = builder
+            .generate()
+            .unwrap_or_else(|err| {
+                panic!(
+                    "ublk-sys: unable to generate bindings for {}: {err}",
+                    header.name
+                )
+            })
+            .to_string();
+
+        for union in header.unions {
+            code = rename_union(&code, union);
+        }
+        std::fs::write(out_dir.join(header.out), code).unwrap();
+    }
+
+    /// Names of the constants and structures of the module `path`
+    fn ext_items(path: &str) -> Vec<String> {
+        let code = std::fs::read_to_string(path).unwrap();
+        code.lines()
+            .filter_map(|line| {
+                let rest = line
+                    .strip_prefix("pub const ")
+                    .or_else(|| line.strip_prefix("pub struct "))?;
+                let end = rest.find([':', ' ', '{']).unwrap_or(rest.len());
+                Some(rest[..end].to_string())
+            })
+            .collect()
+    }
+
+    /// Replaces the identifier `from` by `to`, leaving longer identifiers
+    /// that start with `from` alone
+    fn replace_ident(code: &str, from: &str, to: &str) -> String {
+        let is_ident = |c: char| c == '_' || c.is_ascii_alphanumeric();
+        let mut out = String::with_capacity(code.len());
+        let mut rest = code;
+        while let Some(pos) = rest.find(from) {
+            out.push_str(&rest[..pos]);
+            let before = out.chars().last();
+            let after = rest[pos + from.len()..].chars().next();
+            if before.is_some_and(is_ident) || after.is_some_and(is_ident) {
+                out.push_str(from);
+            } else {
+                out.push_str(to);
+            }
+            rest = &rest[pos + from.len()..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Gives an anonymous union the field and type names of the
+    /// hand-written layer, so that code using it builds either way
+    fn rename_union(code: &str, union: &Union) -> String {
+        let code = replace_ident(
+            code,
+            &format!("{}__bindgen_ty_{}", union.owner, union.n),
+            union.ty,
+        );
+
+        // the field is only renamed in the owner's definition, other
+        // structures have their own `__bindgen_anon_N`
+        let start = format!("pub struct {} {{", union.owner);
+        let Some(begin) = code.find(&start) else {
+            return code;
+        };
+        let end = begin + code[begin..].find("\n}").unwrap_or(code.len() - begin);
+        let body = replace_ident(
+            &code[begin..end],
+            &format!("__bindgen_anon_{}", union.n),
+            union.field,
+        );
+        format!("{}{}{}", &code[..begin], body, &code[end..])
+    }
+
+    pub fn generate_all(include_paths: &[PathBuf]) {
+        let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+        for header in HEADERS {
+            // without libublksrv, `cmd` comes from the kernel uapi header
+            if !cfg!(feature = "libublksrv") {
+                if header.out == "ublk_cmd.rs" {
+                    let uapi = Header {
+                        name: "linux/ublk_cmd.h",
+                        ..*header
+                    };
+                    generate(&uapi, include_paths, &out_dir);
+                }
+                continue;
+            }
+            generate(header, include_paths, &out_dir);
+        }
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "vendored")]
+    let include_paths = vendored::build();
+
+    #[cfg(all(feature = "libublksrv", not(feature = "vendored")))]
+    let include_paths = {
+        let uring = link(&LIBURING);
+        select_liburing_layout(uring.version.as_deref());
+
+        let mut paths = link(&LIBUBLKSRV).include_paths;
+        paths.extend(uring.include_paths);
+        paths
+    };
+
+    // Only `cmd`, `ctrl` and `queue` are built, nothing is linked
+    #[cfg(not(feature = "libublksrv"))]
+    let include_paths: Vec<PathBuf> = {
+        select_liburing_layout(None);
+        Vec::new()
+    };
+
+    #[cfg(feature = "bindgen")]
+    bindings::generate_all(&include_paths);
+
+    // Exported to dependents as DEP_UBLKSRV_INCLUDE
+    if let Ok(include) = env::join_paths(&include_paths) {
+        println!("cargo:include={}", include.to_string_lossy());
+    }
+}
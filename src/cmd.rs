@@ -1,197 +1,920 @@
 // SPDX-License-Identifier: MIT
-#![allow(dead_code)]
-#![allow(non_camel_case_types)]
 #![allow(clippy::missing_safety_doc)] // FIXME
-use libc::{__s32, __u16, __u32, __u64, __u8};
-
-/// Admin commands, issued by ublk server, and handled by ublk driver.
-pub const UBLK_CMD_GET_QUEUE_AFFINITY: u32 = 1;
-pub const UBLK_CMD_GET_DEV_INFO: u32 = 2;
-pub const UBLK_CMD_ADD_DEV: u32 = 4;
-pub const UBLK_CMD_DEL_DEV: u32 = 5;
-pub const UBLK_CMD_START_DEV: u32 = 6;
-pub const UBLK_CMD_STOP_DEV: u32 = 7;
-pub const UBLK_CMD_SET_PARAMS: u32 = 8;
-pub const UBLK_CMD_GET_PARAMS: u32 = 9;
-
-/// IO commands, issued by ublk server, and handled by ublk driver.
-///
-/// FETCH_REQ: issued via sqe(URING_CMD) beforehand for fetching IO request
-///      from ublk driver, should be issued only when starting device. After
-///      the associated cqe is returned, request's tag can be retrieved via
-///      cqe->userdata.
-///
-/// COMMIT_AND_FETCH_REQ: issued via sqe(URING_CMD) after ublkserver handled
-///      this IO request, request's handling result is committed to ublk
-///      driver, meantime FETCH_REQ is piggyback, and FETCH_REQ has to be
-///      handled before completing io request.
-///
-/// NEED_GET_DATA: only used for write requests to set io addr and copy data
-///      When NEED_GET_DATA is set, ublksrv has to issue UBLK_IO_NEED_GET_DATA
-///      command after ublk driver returns UBLK_IO_RES_NEED_GET_DATA.
-///
-///      It is only used if ublksrv set UBLK_F_NEED_GET_DATA flag while starting a ublk device.
-pub const UBLK_IO_FETCH_REQ: u32 = 32;
-pub const UBLK_IO_COMMIT_AND_FETCH_REQ: u32 = 33;
-pub const UBLK_IO_NEED_GET_DATA: u32 = 34;
+use libc::{__u16, __u32, __u64, __u8};
+use std::fs::File;
+use std::io;
+use std::mem::{offset_of, size_of, ManuallyDrop};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 
-/// only ABORT means that no re-fetch
-pub const UBLK_IO_RES_OK: u32 = 0;
-pub const UBLK_IO_RES_NEED_GET_DATA: u32 = 1;
-pub const UBLK_IO_RES_ABORT: i32 = -libc::ENODEV;
+#[cfg(not(feature = "bindgen"))]
+mod raw;
+/// Generated from `ublk_cmd.h` at build time
+#[cfg(feature = "bindgen")]
+mod raw {
+    #![allow(non_camel_case_types, non_upper_case_globals, dead_code)]
+    use libc::{__s32, __u16, __u32, __u64, __u8};
+    include!(concat!(env!("OUT_DIR"), "/ublk_cmd.rs"));
+}
+pub use raw::*;
+mod ext;
+pub use ext::*;
 
-pub const UBLKSRV_CMD_BUF_OFFSET: u32 = 0;
-pub const UBLKSRV_IO_BUF_OFFSET: u32 = 0x80000000;
+pub unsafe fn ublksrv_get_op(iod: *const ublksrv_io_desc) -> __u8 {
+    ((*iod).op_flags & 0xff) as __u8
+}
 
-/// tag bit is 12bit, so at most 4096 IOs for each queue
-pub const UBLK_MAX_QUEUE_DEPTH: u32 = 4096;
+pub unsafe fn ublksrv_get_flags(iod: *const ublksrv_io_desc) -> __u32 {
+    (*iod).op_flags >> 8
+}
+
+/// Operation of a request, `UBLK_IO_OP_*`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum IoOp {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteSame,
+    WriteZeroes,
+    ZoneOpen,
+    ZoneClose,
+    ZoneFinish,
+    ZoneAppend,
+    ZoneResetAll,
+    ZoneReset,
+    ReportZones,
+    Unknown(u8),
+}
 
-/// zero copy requires 4k block size, and can remap ublk driver's io
-/// request into ublksrv's vm space
-pub const UBLK_F_SUPPORT_ZERO_COPY: u32 = 1 << 0;
+impl From<u8> for IoOp {
+    fn from(op: u8) -> Self {
+        match op as u32 {
+            UBLK_IO_OP_READ => IoOp::Read,
+            UBLK_IO_OP_WRITE => IoOp::Write,
+            UBLK_IO_OP_FLUSH => IoOp::Flush,
+            UBLK_IO_OP_DISCARD => IoOp::Discard,
+            UBLK_IO_OP_WRITE_SAME => IoOp::WriteSame,
+            UBLK_IO_OP_WRITE_ZEROES => IoOp::WriteZeroes,
+            UBLK_IO_OP_ZONE_OPEN => IoOp::ZoneOpen,
+            UBLK_IO_OP_ZONE_CLOSE => IoOp::ZoneClose,
+            UBLK_IO_OP_ZONE_FINISH => IoOp::ZoneFinish,
+            UBLK_IO_OP_ZONE_APPEND => IoOp::ZoneAppend,
+            UBLK_IO_OP_ZONE_RESET_ALL => IoOp::ZoneResetAll,
+            UBLK_IO_OP_ZONE_RESET => IoOp::ZoneReset,
+            UBLK_IO_OP_REPORT_ZONES => IoOp::ReportZones,
+            _ => IoOp::Unknown(op),
+        }
+    }
+}
 
-/// Force to complete io cmd via io_uring_cmd_complete_in_task so that
-/// performance comparison is done easily with using task_work_add
-pub const UBLK_F_URING_CMD_COMP_IN_TASK: u32 = 1 << 1;
+impl From<IoOp> for u8 {
+    fn from(op: IoOp) -> Self {
+        let op = match op {
+            IoOp::Read => UBLK_IO_OP_READ,
+            IoOp::Write => UBLK_IO_OP_WRITE,
+            IoOp::Flush => UBLK_IO_OP_FLUSH,
+            IoOp::Discard => UBLK_IO_OP_DISCARD,
+            IoOp::WriteSame => UBLK_IO_OP_WRITE_SAME,
+            IoOp::WriteZeroes => UBLK_IO_OP_WRITE_ZEROES,
+            IoOp::ZoneOpen => UBLK_IO_OP_ZONE_OPEN,
+            IoOp::ZoneClose => UBLK_IO_OP_ZONE_CLOSE,
+            IoOp::ZoneFinish => UBLK_IO_OP_ZONE_FINISH,
+            IoOp::ZoneAppend => UBLK_IO_OP_ZONE_APPEND,
+            IoOp::ZoneResetAll => UBLK_IO_OP_ZONE_RESET_ALL,
+            IoOp::ZoneReset => UBLK_IO_OP_ZONE_RESET,
+            IoOp::ReportZones => UBLK_IO_OP_REPORT_ZONES,
+            IoOp::Unknown(op) => return op,
+        };
+        op as u8
+    }
+}
 
-/// User should issue io cmd again for write requests to
-/// set io buffer address and copy data from bio vectors
-/// to the userspace io buffer.
+/// Flags of a request, `UBLK_IO_F_*`.
 ///
-/// In this mode, task_work is not used.
-pub const UBLK_F_NEED_GET_DATA: u32 = 1 << 2;
+/// They are kept at their place in `ublksrv_io_desc::op_flags`, unlike the
+/// value of `ublksrv_get_flags()` which is shifted.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct IoFlags(u32);
 
-/// device state
-pub const UBLK_S_DEV_DEAD: u32 = 0;
-pub const UBLK_S_DEV_LIVE: u32 = 1;
+impl IoFlags {
+    pub const FAILFAST_DEV: IoFlags = IoFlags(UBLK_IO_F_FAILFAST_DEV);
+    pub const FAILFAST_TRANSPORT: IoFlags = IoFlags(UBLK_IO_F_FAILFAST_TRANSPORT);
+    pub const FAILFAST_DRIVER: IoFlags = IoFlags(UBLK_IO_F_FAILFAST_DRIVER);
+    pub const META: IoFlags = IoFlags(UBLK_IO_F_META);
+    pub const FUA: IoFlags = IoFlags(UBLK_IO_F_FUA);
+    pub const NOUNMAP: IoFlags = IoFlags(UBLK_IO_F_NOUNMAP);
+    pub const SWAP: IoFlags = IoFlags(UBLK_IO_F_SWAP);
+    pub const NEED_REG_BUF: IoFlags = IoFlags(UBLK_IO_F_NEED_REG_BUF);
 
-/// shipped via sqe->cmd of io_uring command
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-pub struct ublksrv_ctrl_cmd {
-    /// sent to which device, must be valid
-    pub dev_id: __u32,
-    /// sent to which queue, must be -1 if the cmd isn't for queue
-    pub queue_id: __u16,
-    /// cmd specific buffer, can be IN or OUT
-    pub len: __u16,
-    pub addr: __u64,
-    /// inline data
-    pub data: [__u64; 2],
+    /// Flags of `op_flags`, its op bits are dropped
+    pub const fn from_op_flags(op_flags: u32) -> Self {
+        IoFlags(op_flags & !0xff)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: IoFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl std::ops::BitOr for IoFlags {
+    type Output = IoFlags;
+
+    fn bitor(self, rhs: IoFlags) -> IoFlags {
+        IoFlags(self.0 | rhs.0)
+    }
+}
+
+impl ublksrv_io_desc {
+    pub fn op(&self) -> IoOp {
+        IoOp::from((self.op_flags & 0xff) as u8)
+    }
+
+    pub fn flags(&self) -> IoFlags {
+        IoFlags::from_op_flags(self.op_flags)
+    }
+
+    /// Byte offset of the request.
+    ///
+    /// The driver counts in sectors of 512 bytes, whatever the logical
+    /// block size of the device.
+    pub fn byte_offset(&self) -> u64 {
+        self.start_sector << 9
+    }
+
+    /// Byte length of the request's data, not meaningful for
+    /// UBLK_IO_OP_REPORT_ZONES
+    pub fn byte_len(&self) -> u64 {
+        (self.nr_sectors as u64) << 9
+    }
+
+    /// First logical block of the request, for a device whose
+    /// `ublk_param_basic::logical_bs_shift` is `logical_bs_shift`
+    pub fn start_block(&self, logical_bs_shift: u8) -> u64 {
+        self.byte_offset() >> logical_bs_shift
+    }
+
+    /// Number of logical blocks of the request, see `start_block()`
+    pub fn nr_blocks(&self, logical_bs_shift: u8) -> u64 {
+        self.byte_len() >> logical_bs_shift
+    }
+
+    /// Number of zones to report, for UBLK_IO_OP_REPORT_ZONES
+    pub fn nr_zones(&self) -> u32 {
+        self.nr_sectors
+    }
+}
+
+impl ublksrv_io_cmd {
+    /// Sector the data of a UBLK_IO_OP_ZONE_APPEND request was written at,
+    /// sent when committing it
+    pub fn set_zone_append_lba(&mut self, lba: u64) {
+        self.addr = lba;
+    }
 }
 
+impl ublk_auto_buf_reg {
+    /// Encoding in `ublksrv_io_cmd::addr`
+    pub const fn to_sqe_addr(&self) -> u64 {
+        self.index as u64
+            | (self.flags as u64) << 16
+            | (self.reserved0 as u64) << 24
+            | (self.reserved1 as u64) << 32
+    }
+
+    pub const fn from_sqe_addr(addr: u64) -> Self {
+        ublk_auto_buf_reg {
+            index: addr as u16,
+            flags: (addr >> 16) as u8,
+            reserved0: (addr >> 24) as u8,
+            reserved1: (addr >> 32) as u32,
+        }
+    }
+}
+
+/// `enum blk_zone_type`, of `linux/blkzoned.h`
+pub const BLK_ZONE_TYPE_CONVENTIONAL: u8 = 0x1;
+pub const BLK_ZONE_TYPE_SEQWRITE_REQ: u8 = 0x2;
+pub const BLK_ZONE_TYPE_SEQWRITE_PREF: u8 = 0x3;
+
+/// `enum blk_zone_cond`, of `linux/blkzoned.h`
+pub const BLK_ZONE_COND_NOT_WP: u8 = 0x0;
+pub const BLK_ZONE_COND_EMPTY: u8 = 0x1;
+pub const BLK_ZONE_COND_IMP_OPEN: u8 = 0x2;
+pub const BLK_ZONE_COND_EXP_OPEN: u8 = 0x3;
+pub const BLK_ZONE_COND_CLOSED: u8 = 0x4;
+pub const BLK_ZONE_COND_READONLY: u8 = 0xd;
+pub const BLK_ZONE_COND_FULL: u8 = 0xe;
+pub const BLK_ZONE_COND_OFFLINE: u8 = 0xf;
+
+/// Zone descriptor of a UBLK_IO_OP_REPORT_ZONES report, `struct blk_zone`
+/// of `linux/blkzoned.h`
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
-pub struct ublksrv_ctrl_dev_info {
-    pub nr_hw_queues: __u16,
-    pub queue_depth: __u16,
-    pub state: __u16,
-    pub pad0: __u16,
-    pub max_io_buf_bytes: __u32,
-    pub dev_id: __u32,
-    pub ublksrv_pid: __s32,
-    pub pad1: __u32,
-    pub flags: __u64,
-    /// For ublksrv internal use, invisible to ublk driver
-    pub ublksrv_flags: __u64,
-    pub reserved0: __u64,
-    pub reserved1: __u64,
-    pub reserved2: __u64,
-}
-
-pub const UBLK_IO_OP_READ: u32 = 0;
-pub const UBLK_IO_OP_WRITE: u32 = 1;
-pub const UBLK_IO_OP_FLUSH: u32 = 2;
-pub const UBLK_IO_OP_DISCARD: u32 = 3;
-pub const UBLK_IO_OP_WRITE_SAME: u32 = 4;
-pub const UBLK_IO_OP_WRITE_ZEROES: u32 = 5;
-
-pub const UBLK_IO_F_FAILFAST_DEV: u32 = 1 << 8;
-pub const UBLK_IO_F_FAILFAST_TRANSPORT: u32 = 1 << 9;
-pub const UBLK_IO_F_FAILFAST_DRIVER: u32 = 1 << 10;
-pub const UBLK_IO_F_META: u32 = 1 << 11;
-pub const UBLK_IO_F_FUA: u32 = 1 << 13;
-pub const UBLK_IO_F_NOUNMAP: u32 = 1 << 15;
-pub const UBLK_IO_F_SWAP: u32 = 1 << 16;
-
-/// io cmd is described by this structure, and stored in share memory, indexed by request tag.
+pub struct blk_zone {
+    /// Zone start sector
+    pub start: __u64,
+    /// Zone length in number of sectors
+    pub len: __u64,
+    /// Zone write pointer position
+    pub wp: __u64,
+    /// Zone type, `BLK_ZONE_TYPE_*`
+    pub type_: __u8,
+    /// Zone condition, `BLK_ZONE_COND_*`
+    pub cond: __u8,
+    /// Non-sequential write resources active
+    pub non_seq: __u8,
+    /// Reset write pointer recommended
+    pub reset: __u8,
+    pub resv: [__u8; 4],
+    /// Zone capacity in number of sectors
+    pub capacity: __u64,
+    pub reserved: [__u8; 24],
+}
+
+/// Reply of a UBLK_IO_OP_REPORT_ZONES request, written to its buffer.
+#[derive(Debug, Clone)]
+pub struct ZoneReport {
+    zones: Vec<blk_zone>,
+    len: usize,
+}
+
+impl ZoneReport {
+    /// Report of at most `nr_zones` zones, `ublksrv_io_desc::nr_zones()` of
+    /// the request
+    pub fn new(nr_zones: u32) -> Self {
+        ZoneReport {
+            zones: vec![blk_zone::default(); nr_zones as usize],
+            len: 0,
+        }
+    }
+
+    /// Adds `zone`, returns false if the report is full
+    pub fn push(&mut self, zone: blk_zone) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.zones[self.len] = zone;
+        self.len += 1;
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.zones.len()
+    }
+
+    pub fn zones(&self) -> &[blk_zone] {
+        &self.zones[..self.len]
+    }
+
+    /// The report as the driver expects it: the zones, followed by a zeroed
+    /// entry if there are fewer than requested.
+    pub fn as_bytes(&self) -> &[u8] {
+        let nr = (self.len + 1).min(self.zones.len());
+        // blk_zone has no padding
+        unsafe {
+            std::slice::from_raw_parts(
+                self.zones.as_ptr() as *const u8,
+                nr * std::mem::size_of::<blk_zone>(),
+            )
+        }
+    }
+}
+
+/// Prefix of the char device path of a ublk device, followed by its id
+pub const UBLKC_DEV_PREFIX: &str = "/dev/ublkc";
+/// Prefix of the block device path of a ublk device, followed by its id
+pub const UBLKB_DEV_PREFIX: &str = "/dev/ublkb";
+
+impl ublk_param_devt {
+    pub fn char_devt(&self) -> libc::dev_t {
+        libc::makedev(self.char_major, self.char_minor)
+    }
+
+    pub fn disk_devt(&self) -> libc::dev_t {
+        libc::makedev(self.disk_major, self.disk_minor)
+    }
+
+    /// Char device node of the device in `dev_dir`, usually `/dev`.
+    ///
+    /// The node is looked up by device number, so this works whatever it is
+    /// named, e.g. in a container with its own `/dev`.
+    pub fn char_path(&self, dev_dir: &Path) -> io::Result<PathBuf> {
+        find_dev_node(dev_dir, self.char_devt(), false)
+    }
+
+    /// Same as `char_path()`, for the block device
+    pub fn disk_path(&self, dev_dir: &Path) -> io::Result<PathBuf> {
+        find_dev_node(dev_dir, self.disk_devt(), true)
+    }
+}
+
+fn find_dev_node(dev_dir: &Path, devt: libc::dev_t, block: bool) -> io::Result<PathBuf> {
+    for entry in dev_dir.read_dir()? {
+        let entry = entry?;
+        // symlinks are skipped, so the node itself is returned
+        let Ok(meta) = entry.path().symlink_metadata() else {
+            continue;
+        };
+        let ty = meta.file_type();
+        let is_dev = if block {
+            ty.is_block_device()
+        } else {
+            ty.is_char_device()
+        };
+
+        if is_dev && meta.rdev() == devt {
+            return Ok(entry.path());
+        }
+    }
+
+    Err(io::Error::from(io::ErrorKind::NotFound))
+}
+
+/// End of each parameter group in `ublk_params`, by `UBLK_PARAM_TYPE_*`
+const PARAM_GROUPS: [(u32, usize); 6] = [
+    (
+        UBLK_PARAM_TYPE_BASIC,
+        offset_of!(ublk_params, basic) + size_of::<ublk_param_basic>(),
+    ),
+    (
+        UBLK_PARAM_TYPE_DISCARD,
+        offset_of!(ublk_params, discard) + size_of::<ublk_param_discard>(),
+    ),
+    (
+        UBLK_PARAM_TYPE_DEVT,
+        offset_of!(ublk_params, devt) + size_of::<ublk_param_devt>(),
+    ),
+    (
+        UBLK_PARAM_TYPE_ZONED,
+        offset_of!(ublk_params, zoned) + size_of::<ublk_param_zoned>(),
+    ),
+    (
+        UBLK_PARAM_TYPE_DMA_ALIGN,
+        offset_of!(ublk_params, dma) + size_of::<ublk_param_dma_align>(),
+    ),
+    (
+        UBLK_PARAM_TYPE_SEGMENT,
+        offset_of!(ublk_params, seg) + size_of::<ublk_param_segment>(),
+    ),
+];
+
+/// Size of the `len` and `types` header of `ublk_params`
+const PARAMS_HEADER_LEN: usize = offset_of!(ublk_params, basic);
+
+impl ublk_params {
+    /// Empty params, whose `len` is the size of the struct known here
+    pub fn new() -> Self {
+        ublk_params {
+            len: size_of::<ublk_params>() as __u32,
+            ..Default::default()
+        }
+    }
+
+    /// The `UBLK_PARAM_TYPE_*` groups lying within the first `len` bytes
+    /// of the struct
+    pub fn types_within(len: usize) -> u32 {
+        PARAM_GROUPS
+            .iter()
+            .filter(|&&(_, end)| end <= len)
+            .fold(0, |types, &(ty, _)| types | ty)
+    }
+
+    /// Params as sent to the driver, `len` bytes of them.
+    ///
+    /// The struct has padding before `seg`, so it is copied field by field
+    /// into a zeroed buffer instead of being sent as is.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        /// the groups themselves have no padding
+        fn put<T: Copy>(buf: &mut [u8], offset: usize, val: &T) {
+            let bytes =
+                unsafe { std::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+            buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        let mut buf = vec![0; size_of::<ublk_params>()];
+        put(&mut buf, offset_of!(ublk_params, len), &self.len);
+        put(&mut buf, offset_of!(ublk_params, types), &self.types);
+        put(&mut buf, offset_of!(ublk_params, basic), &self.basic);
+        put(&mut buf, offset_of!(ublk_params, discard), &self.discard);
+        put(&mut buf, offset_of!(ublk_params, devt), &self.devt);
+        put(&mut buf, offset_of!(ublk_params, zoned), &self.zoned);
+        put(&mut buf, offset_of!(ublk_params, dma), &self.dma);
+        put(&mut buf, offset_of!(ublk_params, seg), &self.seg);
+
+        buf.truncate(self.len as usize);
+        buf
+    }
+
+    /// Params read back from the driver into `buf`.
+    ///
+    /// Only the length the driver reported in the `len` of `buf` is read,
+    /// the rest is left zeroed. `len` and `types` are trimmed to what this
+    /// crate and the driver both know, so a group is in `types` only if it
+    /// was set and entirely copied.
+    pub fn from_driver(buf: &[u8]) -> Self {
+        let mut params = ublk_params::default();
+        if buf.len() < PARAMS_HEADER_LEN {
+            return params;
+        }
+
+        let reported = u32::from_ne_bytes(buf[..4].try_into().unwrap()) as usize;
+        let len = reported.min(buf.len()).min(size_of::<ublk_params>());
+        if len < PARAMS_HEADER_LEN {
+            return params;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), &mut params as *mut Self as *mut u8, len)
+        };
+
+        params.len = len as __u32;
+        params.types &= Self::types_within(len);
+        params
+    }
+}
+
+/// Command buffer of a control command for an unprivileged device
+/// (`UBLK_F_UNPRIVILEGED_DEV`).
 ///
-/// The data is stored by ublk driver, and read by ublksrv after one fetch command returns.
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-pub struct ublksrv_io_desc {
-    /// op: bit 0-7, flags: bit 8-31
-    pub op_flags: __u32,
-    pub nr_sectors: __u32,
-    /// start sector for this io
-    pub start_sector: __u64,
-    /// buffer address in ublksrv daemon vm space, from ublk driver
-    pub addr: __u64,
+/// The driver checks the permission of the caller on the device's char
+/// device, so its path is sent, null terminated, in front of the command's
+/// own payload. `dev_path_len` tells the driver where the payload starts.
+///
+/// libublksrv does this by itself (and uses `UBLK_CMD_GET_DEV_INFO2` for
+/// `ublksrv_ctrl_get_info`) when `UBLK_F_UNPRIVILEGED_DEV` is set in
+/// `ublksrv_dev_data::flags`, this is for commands sent without it.
+#[derive(Debug, Clone)]
+pub struct UnprivCtrlBuf {
+    buf: Vec<u8>,
+    dev_path_len: usize,
 }
 
-pub unsafe fn ublksrv_get_op(iod: *const ublksrv_io_desc) -> __u8 {
-    ((*iod).op_flags & 0xff) as __u8
+impl UnprivCtrlBuf {
+    /// Buffer for `dev_path` followed by `payload_len` zeroed bytes
+    pub fn new(dev_path: &Path, payload_len: usize) -> io::Result<Self> {
+        let path = dev_path.as_os_str().as_bytes();
+        let dev_path_len = path.len() + 1;
+
+        if path.contains(&0) || dev_path_len > libc::PATH_MAX as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        if dev_path_len + payload_len > u16::MAX as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let mut buf = vec![0; dev_path_len + payload_len];
+        buf[..path.len()].copy_from_slice(path);
+        Ok(UnprivCtrlBuf { buf, dev_path_len })
+    }
+
+    /// Buffer for the char device of `dev_id`, `/dev/ublkc<dev_id>`
+    pub fn for_dev(dev_id: u32, payload_len: usize) -> io::Result<Self> {
+        Self::new(
+            Path::new(&format!("{UBLKC_DEV_PREFIX}{dev_id}")),
+            payload_len,
+        )
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[self.dev_path_len..]
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.dev_path_len..]
+    }
+
+    /// Points `cmd` to this buffer, which has to outlive the command.
+    pub fn fill_cmd(&mut self, cmd: &mut ublksrv_ctrl_cmd) {
+        cmd.addr = self.buf.as_mut_ptr() as __u64;
+        cmd.len = self.buf.len() as __u16;
+        cmd.dev_path_len = self.dev_path_len as __u16;
+    }
 }
 
-pub unsafe fn ublksrv_get_flags(iod: *const ublksrv_io_desc) -> __u32 {
-    (*iod).op_flags >> 8
+/// Position in the char device of byte `offset` of the buffer of request
+/// `tag` of queue `q_id`, for `UBLK_F_USER_COPY`.
+///
+/// Panics if `q_id` or `offset` are out of range.
+pub const fn ublk_user_copy_pos(q_id: u16, tag: u16, offset: u32) -> u64 {
+    assert!(q_id as u64 <= UBLK_QID_BITS_MASK && offset as u64 <= UBLK_IO_BUF_BITS_MASK);
+    UBLKSRV_IO_BUF_OFFSET as u64
+        + ((q_id as u64) << UBLK_QID_OFF)
+        + ((tag as u64) << UBLK_TAG_OFF)
+        + ((offset as u64) << UBLK_IO_BUF_OFF)
 }
 
-/// issued to ublk driver via /dev/ublkcN
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-pub struct ublksrv_io_cmd {
-    pub q_id: __u16,
-    /// for fetch/commit which result
-    pub tag: __u16,
-    /// io result, it is valid for COMMIT* command only
-    pub result: __s32,
-    /// userspace buffer address in ublksrv daemon process, valid for * FETCH* command only
-    pub addr: __u64,
+/// Copies the requests' data of a `UBLK_F_USER_COPY` device, through its
+/// char device.
+///
+/// The data of a WRITE request is read from the device, the data of a READ
+/// request is written to it, before the request is committed.
+#[derive(Debug, Clone, Copy)]
+pub struct UserCopy<'a> {
+    cdev: BorrowedFd<'a>,
 }
 
-pub const UBLK_ATTR_READ_ONLY: u32 = 1 << 0;
-pub const UBLK_ATTR_ROTATIONAL: u32 = 1 << 1;
-pub const UBLK_ATTR_VOLATILE_CACHE: u32 = 1 << 2;
-pub const UBLK_ATTR_FUA: u32 = 1 << 3;
+impl<'a> UserCopy<'a> {
+    pub fn new(cdev: BorrowedFd<'a>) -> Self {
+        UserCopy { cdev }
+    }
 
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-pub struct ublk_param_basic {
-    pub attrs: __u32,
-    pub logical_bs_shift: __u8,
-    pub physical_bs_shift: __u8,
-    pub io_opt_shift: __u8,
-    pub io_min_shift: __u8,
-    pub max_sectors: __u32,
-    pub chunk_sectors: __u32,
-    pub dev_sectors: __u64,
-    pub virt_boundary_mask: __u64,
+    fn pos(q_id: u16, tag: u16, offset: u32, len: usize) -> io::Result<u64> {
+        if q_id as u64 > UBLK_QID_BITS_MASK
+            || offset as u64 + len as u64 > UBLK_IO_BUF_BITS_MASK + 1
+        {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        Ok(ublk_user_copy_pos(q_id, tag, offset))
+    }
+
+    fn with_file<T>(&self, f: impl FnOnce(&File) -> T) -> T {
+        // the fd is borrowed, it must not be closed with the File
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(self.cdev.as_raw_fd()) });
+        f(&file)
+    }
+
+    /// Fills `buf` with the data of request `tag` of queue `q_id`, from
+    /// byte `offset`.
+    pub fn read(&self, q_id: u16, tag: u16, offset: u32, buf: &mut [u8]) -> io::Result<()> {
+        let pos = Self::pos(q_id, tag, offset, buf.len())?;
+        self.with_file(|f| f.read_exact_at(buf, pos))
+    }
+
+    /// Writes `buf` to the data of request `tag` of queue `q_id`, from
+    /// byte `offset`.
+    pub fn write(&self, q_id: u16, tag: u16, offset: u32, buf: &[u8]) -> io::Result<()> {
+        let pos = Self::pos(q_id, tag, offset, buf.len())?;
+        self.with_file(|f| f.write_all_at(buf, pos))
+    }
 }
 
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-pub struct ublk_param_discard {
-    pub discard_alignment: __u32,
-    pub discard_granularity: __u32,
-    pub max_discard_sectors: __u32,
-    pub max_write_zeroes_sectors: __u32,
-    pub max_discard_segments: __u16,
-    pub reserved0: __u16,
+/// ioctl number encoding of `asm-generic/ioctl.h`, with the values of the
+/// architectures that override it
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+))]
+mod ioc {
+    pub const SIZEBITS: u32 = 13;
+    pub const WRITE: u32 = 4;
+    pub const READ: u32 = 2;
+}
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+)))]
+mod ioc {
+    pub const SIZEBITS: u32 = 14;
+    pub const WRITE: u32 = 1;
+    pub const READ: u32 = 2;
 }
 
-pub const UBLK_PARAM_TYPE_BASIC: u32 = 1 << 0;
-pub const UBLK_PARAM_TYPE_DISCARD: u32 = 1 << 1;
+const fn ioc(dir: u32, ty: u8, nr: u32, size: usize) -> u32 {
+    const NRSHIFT: u32 = 0;
+    const TYPESHIFT: u32 = 8;
+    const SIZESHIFT: u32 = 16;
+    const DIRSHIFT: u32 = SIZESHIFT + ioc::SIZEBITS;
 
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-pub struct ublk_params {
-    /// Total length of parameters, userspace has to set 'len' for both
-    /// SET_PARAMS and GET_PARAMS command, and driver may update len
-    /// if two sides use different version of 'ublk_params', same with
-    /// 'types' fields.
-    pub len: __u32,
-    pub types: __u32,
-    pub basic: ublk_param_basic,
-    pub discard: ublk_param_discard,
+    (dir << DIRSHIFT) | ((ty as u32) << TYPESHIFT) | (nr << NRSHIFT) | ((size as u32) << SIZESHIFT)
+}
+
+/// `_IOR(ty, nr, size)`
+pub const fn ior(ty: u8, nr: u32, size: usize) -> u32 {
+    ioc(ioc::READ, ty, nr, size)
+}
+
+/// `_IOWR(ty, nr, size)`
+pub const fn iowr(ty: u8, nr: u32, size: usize) -> u32 {
+    ioc(ioc::READ | ioc::WRITE, ty, nr, size)
+}
+
+/// ioctl type of the ublk commands
+pub const UBLK_IOC_TYPE: u8 = b'u';
+
+const CTRL_CMD_SIZE: usize = std::mem::size_of::<ublksrv_ctrl_cmd>();
+const IO_CMD_SIZE: usize = std::mem::size_of::<ublksrv_io_cmd>();
+
+/// Admin commands, ioctl encoded (`UBLK_F_CMD_IOCTL_ENCODE`)
+pub const UBLK_U_CMD_GET_QUEUE_AFFINITY: u32 =
+    ior(UBLK_IOC_TYPE, UBLK_CMD_GET_QUEUE_AFFINITY, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_GET_DEV_INFO: u32 = ior(UBLK_IOC_TYPE, UBLK_CMD_GET_DEV_INFO, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_ADD_DEV: u32 = iowr(UBLK_IOC_TYPE, UBLK_CMD_ADD_DEV, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_DEL_DEV: u32 = iowr(UBLK_IOC_TYPE, UBLK_CMD_DEL_DEV, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_START_DEV: u32 = iowr(UBLK_IOC_TYPE, UBLK_CMD_START_DEV, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_STOP_DEV: u32 = iowr(UBLK_IOC_TYPE, UBLK_CMD_STOP_DEV, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_SET_PARAMS: u32 = iowr(UBLK_IOC_TYPE, UBLK_CMD_SET_PARAMS, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_GET_PARAMS: u32 = ior(UBLK_IOC_TYPE, UBLK_CMD_GET_PARAMS, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_START_USER_RECOVERY: u32 =
+    iowr(UBLK_IOC_TYPE, UBLK_CMD_START_USER_RECOVERY, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_END_USER_RECOVERY: u32 =
+    iowr(UBLK_IOC_TYPE, UBLK_CMD_END_USER_RECOVERY, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_GET_DEV_INFO2: u32 = ior(UBLK_IOC_TYPE, UBLK_CMD_GET_DEV_INFO2, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_GET_FEATURES: u32 = ior(UBLK_IOC_TYPE, UBLK_CMD_GET_FEATURES, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_UPDATE_SIZE: u32 = iowr(UBLK_IOC_TYPE, UBLK_CMD_UPDATE_SIZE, CTRL_CMD_SIZE);
+pub const UBLK_U_CMD_QUIESCE_DEV: u32 = iowr(UBLK_IOC_TYPE, UBLK_CMD_QUIESCE_DEV, CTRL_CMD_SIZE);
+
+/// IO commands, ioctl encoded (`UBLK_F_CMD_IOCTL_ENCODE`)
+pub const UBLK_U_IO_FETCH_REQ: u32 = iowr(UBLK_IOC_TYPE, UBLK_IO_FETCH_REQ, IO_CMD_SIZE);
+pub const UBLK_U_IO_COMMIT_AND_FETCH_REQ: u32 =
+    iowr(UBLK_IOC_TYPE, UBLK_IO_COMMIT_AND_FETCH_REQ, IO_CMD_SIZE);
+pub const UBLK_U_IO_NEED_GET_DATA: u32 = iowr(UBLK_IOC_TYPE, UBLK_IO_NEED_GET_DATA, IO_CMD_SIZE);
+pub const UBLK_U_IO_REGISTER_IO_BUF: u32 =
+    iowr(UBLK_IOC_TYPE, UBLK_IO_REGISTER_IO_BUF, IO_CMD_SIZE);
+pub const UBLK_U_IO_UNREGISTER_IO_BUF: u32 =
+    iowr(UBLK_IOC_TYPE, UBLK_IO_UNREGISTER_IO_BUF, IO_CMD_SIZE);
+
+/// Opcode of the admin command `cmd_op` (`UBLK_CMD_*`) for a device with
+/// `dev_flags`: the ioctl encoded one with `UBLK_F_CMD_IOCTL_ENCODE`, the
+/// legacy one otherwise.
+pub const fn ublk_ctrl_cmd_op(dev_flags: u64, cmd_op: u32) -> u32 {
+    if dev_flags & UBLK_F_CMD_IOCTL_ENCODE as u64 == 0 {
+        return cmd_op;
+    }
+
+    match cmd_op {
+        UBLK_CMD_GET_QUEUE_AFFINITY
+        | UBLK_CMD_GET_DEV_INFO
+        | UBLK_CMD_GET_PARAMS
+        | UBLK_CMD_GET_DEV_INFO2
+        | UBLK_CMD_GET_FEATURES => ior(UBLK_IOC_TYPE, cmd_op, CTRL_CMD_SIZE),
+        _ => iowr(UBLK_IOC_TYPE, cmd_op, CTRL_CMD_SIZE),
+    }
+}
+
+/// Same as `ublk_ctrl_cmd_op()`, for the IO commands (`UBLK_IO_*`)
+pub const fn ublk_io_cmd_op(dev_flags: u64, cmd_op: u32) -> u32 {
+    if dev_flags & UBLK_F_CMD_IOCTL_ENCODE as u64 == 0 {
+        return cmd_op;
+    }
+
+    iowr(UBLK_IOC_TYPE, cmd_op, IO_CMD_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params_with(ty: u32) -> ublk_params {
+        let mut params = ublk_params::new();
+        params.types = ty;
+        match ty {
+            UBLK_PARAM_TYPE_BASIC => {
+                params.basic = ublk_param_basic {
+                    attrs: UBLK_ATTR_VOLATILE_CACHE,
+                    logical_bs_shift: 12,
+                    physical_bs_shift: 12,
+                    io_opt_shift: 12,
+                    io_min_shift: 9,
+                    max_sectors: 1024,
+                    chunk_sectors: 256,
+                    dev_sectors: 1 << 30,
+                    virt_boundary_mask: 4095,
+                }
+            }
+            UBLK_PARAM_TYPE_DISCARD => {
+                params.discard = ublk_param_discard {
+                    discard_alignment: 4096,
+                    discard_granularity: 4096,
+                    max_discard_sectors: 1 << 20,
+                    max_write_zeroes_sectors: 1 << 20,
+                    max_discard_segments: 1,
+                    reserved0: 0,
+                }
+            }
+            UBLK_PARAM_TYPE_DEVT => {
+                params.devt = ublk_param_devt {
+                    char_major: 240,
+                    char_minor: 1,
+                    disk_major: 259,
+                    disk_minor: 2,
+                }
+            }
+            UBLK_PARAM_TYPE_ZONED => {
+                params.zoned = ublk_param_zoned {
+                    max_open_zones: 14,
+                    max_active_zones: 14,
+                    max_zone_append_sectors: 1024,
+                    reserved: [0; 20],
+                }
+            }
+            UBLK_PARAM_TYPE_DMA_ALIGN => params.dma.alignment = 511,
+            UBLK_PARAM_TYPE_SEGMENT => {
+                params.seg = ublk_param_segment {
+                    seg_boundary_mask: 0xffff_ffff,
+                    max_segment_size: UBLK_MIN_SEGMENT_SIZE,
+                    max_segments: 128,
+                    pad: [0; 2],
+                }
+            }
+            _ => unreachable!(),
+        }
+        params
+    }
+
+    #[test]
+    fn params_round_trip() {
+        for (ty, end) in PARAM_GROUPS {
+            let bytes = params_with(ty).to_bytes();
+            assert_eq!(bytes.len(), size_of::<ublk_params>());
+
+            let params = ublk_params::from_driver(&bytes);
+            assert_eq!(params.types, ty, "type {ty:#x}");
+            assert_eq!(params.to_bytes(), bytes, "type {ty:#x}");
+
+            // a driver that doesn't know the whole group drops it
+            let mut short = bytes[..end - 1].to_vec();
+            short[..4].copy_from_slice(&((end - 1) as u32).to_ne_bytes());
+            let params = ublk_params::from_driver(&short);
+            assert_eq!(params.types, 0, "type {ty:#x}");
+            assert_eq!(params.len as usize, end - 1);
+        }
+    }
+
+    #[test]
+    fn params_types_within() {
+        assert_eq!(ublk_params::types_within(PARAMS_HEADER_LEN), 0);
+        assert_eq!(
+            ublk_params::types_within(size_of::<ublk_params>()),
+            PARAM_GROUPS.iter().fold(0, |types, &(ty, _)| types | ty)
+        );
+        for (ty, end) in PARAM_GROUPS {
+            assert_ne!(ublk_params::types_within(end) & ty, 0, "type {ty:#x}");
+            assert_eq!(ublk_params::types_within(end - 1) & ty, 0, "type {ty:#x}");
+        }
+    }
+
+    #[test]
+    fn params_padding_is_zeroed() {
+        let mut params = params_with(UBLK_PARAM_TYPE_SEGMENT);
+        params.types = 0;
+        let bytes = params.to_bytes();
+        let pad = offset_of!(ublk_params, dma) + size_of::<ublk_param_dma_align>();
+        assert!(bytes[pad..offset_of!(ublk_params, seg)]
+            .iter()
+            .all(|&b| b == 0));
+
+        params.len = 10;
+        assert_eq!(params.to_bytes().len(), 10);
+    }
+
+    #[test]
+    fn ioctl_encoding() {
+        // values of the kernel's UBLK_U_* macros
+        let table = [
+            (UBLK_U_CMD_GET_QUEUE_AFFINITY, 0x8020_7501),
+            (UBLK_U_CMD_GET_DEV_INFO, 0x8020_7502),
+            (UBLK_U_CMD_ADD_DEV, 0xc020_7504),
+            (UBLK_U_CMD_DEL_DEV, 0xc020_7505),
+            (UBLK_U_CMD_START_DEV, 0xc020_7506),
+            (UBLK_U_CMD_STOP_DEV, 0xc020_7507),
+            (UBLK_U_CMD_SET_PARAMS, 0xc020_7508),
+            (UBLK_U_CMD_GET_PARAMS, 0x8020_7509),
+            (UBLK_U_CMD_START_USER_RECOVERY, 0xc020_7510),
+            (UBLK_U_CMD_END_USER_RECOVERY, 0xc020_7511),
+            (UBLK_U_CMD_GET_DEV_INFO2, 0x8020_7512),
+            (UBLK_U_CMD_GET_FEATURES, 0x8020_7513),
+            (UBLK_U_CMD_UPDATE_SIZE, 0xc020_7515),
+            (UBLK_U_CMD_QUIESCE_DEV, 0xc020_7516),
+            (UBLK_U_IO_FETCH_REQ, 0xc010_7520),
+            (UBLK_U_IO_COMMIT_AND_FETCH_REQ, 0xc010_7521),
+            (UBLK_U_IO_NEED_GET_DATA, 0xc010_7522),
+            (UBLK_U_IO_REGISTER_IO_BUF, 0xc010_7523),
+            (UBLK_U_IO_UNREGISTER_IO_BUF, 0xc010_7524),
+        ];
+        for (op, expected) in table {
+            assert_eq!(op, expected, "{op:#x} != {expected:#x}");
+        }
+    }
+
+    #[test]
+    fn cmd_op_by_dev_flags() {
+        let encode = UBLK_F_CMD_IOCTL_ENCODE as u64;
+        let table = [
+            (UBLK_CMD_ADD_DEV, UBLK_U_CMD_ADD_DEV),
+            (UBLK_CMD_DEL_DEV, UBLK_U_CMD_DEL_DEV),
+            (UBLK_CMD_START_DEV, UBLK_U_CMD_START_DEV),
+            (UBLK_CMD_STOP_DEV, UBLK_U_CMD_STOP_DEV),
+            (UBLK_CMD_GET_QUEUE_AFFINITY, UBLK_U_CMD_GET_QUEUE_AFFINITY),
+            (UBLK_CMD_GET_DEV_INFO, UBLK_U_CMD_GET_DEV_INFO),
+            (UBLK_CMD_GET_DEV_INFO2, UBLK_U_CMD_GET_DEV_INFO2),
+            (UBLK_CMD_SET_PARAMS, UBLK_U_CMD_SET_PARAMS),
+            (UBLK_CMD_GET_PARAMS, UBLK_U_CMD_GET_PARAMS),
+            (UBLK_CMD_START_USER_RECOVERY, UBLK_U_CMD_START_USER_RECOVERY),
+            (UBLK_CMD_END_USER_RECOVERY, UBLK_U_CMD_END_USER_RECOVERY),
+            (UBLK_CMD_GET_FEATURES, UBLK_U_CMD_GET_FEATURES),
+        ];
+        for (op, encoded) in table {
+            assert_eq!(ublk_ctrl_cmd_op(0, op), op);
+            assert_eq!(ublk_ctrl_cmd_op(encode, op), encoded, "{op:#x}");
+        }
+
+        let table = [
+            (UBLK_IO_FETCH_REQ, UBLK_U_IO_FETCH_REQ),
+            (UBLK_IO_COMMIT_AND_FETCH_REQ, UBLK_U_IO_COMMIT_AND_FETCH_REQ),
+            (UBLK_IO_NEED_GET_DATA, UBLK_U_IO_NEED_GET_DATA),
+        ];
+        for (op, encoded) in table {
+            assert_eq!(ublk_io_cmd_op(0, op), op);
+            assert_eq!(ublk_io_cmd_op(encode, op), encoded, "{op:#x}");
+        }
+    }
+
+    #[test]
+    fn user_copy_pos() {
+        let table = [
+            (0, 0, 0, 0x8000_0000),
+            (0, 7, 4096, 0x8e00_1000),
+            (1, 2, 3, 0x0200_8400_0003),
+            (0, u16::MAX, 0, 0x0200_7e00_0000),
+        ];
+        for (q_id, tag, offset, pos) in table {
+            assert_eq!(
+                ublk_user_copy_pos(q_id, tag, offset),
+                pos,
+                "q {q_id} tag {tag}"
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn user_copy_pos_past_buffer() {
+        ublk_user_copy_pos(0, 0, 1 << UBLK_IO_BUF_BITS);
+    }
+
+    #[test]
+    fn io_op_and_flags() {
+        // values of the kernel's UBLK_IO_OP_*
+        let ops = [
+            (0, IoOp::Read),
+            (1, IoOp::Write),
+            (2, IoOp::Flush),
+            (3, IoOp::Discard),
+            (4, IoOp::WriteSame),
+            (5, IoOp::WriteZeroes),
+            (10, IoOp::ZoneOpen),
+            (11, IoOp::ZoneClose),
+            (12, IoOp::ZoneFinish),
+            (13, IoOp::ZoneAppend),
+            (14, IoOp::ZoneResetAll),
+            (15, IoOp::ZoneReset),
+            (18, IoOp::ReportZones),
+            (42, IoOp::Unknown(42)),
+        ];
+        let flags = [
+            IoFlags::FAILFAST_DEV,
+            IoFlags::FAILFAST_TRANSPORT,
+            IoFlags::FAILFAST_DRIVER,
+            IoFlags::META,
+            IoFlags::FUA,
+            IoFlags::NOUNMAP,
+            IoFlags::SWAP,
+            IoFlags::NEED_REG_BUF,
+        ];
+
+        for (raw, op) in ops {
+            assert_eq!(IoOp::from(raw), op);
+            assert_eq!(u8::from(op), raw);
+
+            for flag in flags {
+                let iod = ublksrv_io_desc {
+                    op_flags: raw as u32 | (flag | IoFlags::FUA).bits(),
+                    ..Default::default()
+                };
+                assert_eq!(iod.op(), op);
+                assert!(iod.flags().contains(flag));
+                assert!(iod.flags().contains(IoFlags::FUA));
+                assert_eq!(iod.flags(), IoFlags::from_op_flags(iod.op_flags));
+                assert_eq!(iod.flags().bits() & 0xff, 0);
+            }
+        }
+        assert!(IoFlags::from_op_flags(UBLK_IO_OP_WRITE).is_empty());
+    }
 }
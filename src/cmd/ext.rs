@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: MIT
+//! Items of `ublk_cmd.h` declared by hand for both layers, and left out of
+//! the generated one.
+//!
+//! The header only defines some of them ioctl encoded, or in terms of errno,
+//! which bindgen can't evaluate. The others are missing from headers older
+//! than the crate, 6.8 being the oldest one supported, or are typed there in
+//! a way the crate can't use: bindgen makes the masks `u32`, and turns the
+//! `nr_sectors`/`nr_zones` and `addr`/`zone_append_lba` unions into
+//! anonymous fields.
+#![allow(non_camel_case_types)]
+use super::raw::{ublk_param_basic, ublk_param_discard, UBLK_IO_BUF_BITS, UBLK_QID_BITS};
+use super::raw::{UBLKSRV_IO_BUF_TOTAL_BITS, UBLK_TAG_BITS};
+use libc::{__s32, __u16, __u32, __u64, __u8};
+
+/// Only sent ioctl encoded, see `UBLK_U_CMD_GET_FEATURES`
+pub const UBLK_CMD_GET_FEATURES: u32 = 0x13;
+/// Changes the size of a live device to `data[0]` sectors, only sent ioctl
+/// encoded, see `UBLK_U_CMD_UPDATE_SIZE`
+pub const UBLK_CMD_UPDATE_SIZE: u32 = 0x15;
+/// Stops delivering requests and aborts the queues' commands, leaving the
+/// device quiesced until it is recovered, `data[0]` is a timeout in ms for
+/// the inflight requests. Only sent ioctl encoded, see
+/// `UBLK_U_CMD_QUIESCE_DEV`
+pub const UBLK_CMD_QUIESCE_DEV: u32 = 0x16;
+
+/// Registers the buffer of request `tag` in the io_uring fixed buffer table
+/// of the ring the command is sent on, at index `addr`, so the target can
+/// do its IO on it with fixed buffer operations (zero copy). Only sent ioctl
+/// encoded, see `UBLK_U_IO_REGISTER_IO_BUF`.
+pub const UBLK_IO_REGISTER_IO_BUF: u32 = 0x23;
+/// Undoes UBLK_IO_REGISTER_IO_BUF, before the request is committed
+pub const UBLK_IO_UNREGISTER_IO_BUF: u32 = 0x24;
+
+/// Result of the commands of a queue once the device is stopped: they
+/// aren't fetched again
+pub const UBLK_IO_RES_ABORT: i32 = -libc::ENODEV;
+
+/// Masks of the fields of `ublk_user_copy_pos()`
+pub const UBLK_IO_BUF_BITS_MASK: u64 = (1 << UBLK_IO_BUF_BITS) - 1;
+pub const UBLK_TAG_BITS_MASK: u64 = (1 << UBLK_TAG_BITS) - 1;
+pub const UBLK_QID_BITS_MASK: u64 = (1 << UBLK_QID_BITS) - 1;
+pub const UBLKSRV_IO_BUF_TOTAL_SIZE: u64 = 1 << UBLKSRV_IO_BUF_TOTAL_BITS;
+
+/// Zoned block device, needs `UBLK_PARAM_TYPE_ZONED` and the
+/// `UBLK_IO_OP_ZONE_*`/`UBLK_IO_OP_REPORT_ZONES` operations.
+pub const UBLK_F_ZONED: u32 = 1 << 8;
+
+/// The device can be resized while live with `UBLK_U_CMD_UPDATE_SIZE`
+pub const UBLK_F_UPDATE_SIZE: u32 = 1 << 10;
+
+/// The driver registers each request's buffer itself before delivering it,
+/// at the index passed in `ublksrv_io_cmd::addr` (`ublk_auto_buf_reg`) with
+/// FETCH_REQ and COMMIT_AND_FETCH_REQ, and unregisters it on commit.
+pub const UBLK_F_AUTO_BUF_REG: u32 = 1 << 11;
+
+/// The device can be quiesced with `UBLK_U_CMD_QUIESCE_DEV` and resumed
+/// through the user recovery commands, needs UBLK_F_USER_RECOVERY
+pub const UBLK_F_QUIESCE: u32 = 1 << 12;
+
+/// Each tag is handled by the thread that fetched it, instead of every tag
+/// of a queue by a single thread, so the tags of a queue can be spread
+/// over several threads
+pub const UBLK_F_PER_IO_DAEMON: u32 = 1 << 13;
+
+/// Auto buffer register: if registering fails, the request is delivered
+/// with UBLK_IO_F_NEED_REG_BUF instead of failing, and the daemon has to
+/// register the buffer itself.
+pub const UBLK_AUTO_BUF_REG_FALLBACK: u32 = 1 << 0;
+pub const UBLK_AUTO_BUF_REG_F_MASK: u32 = UBLK_AUTO_BUF_REG_FALLBACK;
+
+/// Passed in `ublksrv_io_cmd::addr` with UBLK_F_AUTO_BUF_REG, see
+/// `ublk_auto_buf_reg::to_sqe_addr()`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublk_auto_buf_reg {
+    /// index for registering the delivered request buffer
+    pub index: __u16,
+    /// UBLK_AUTO_BUF_REG_*
+    pub flags: __u8,
+    pub reserved0: __u8,
+    pub reserved1: __u32,
+}
+
+pub const UBLK_IO_OP_ZONE_OPEN: u32 = 10;
+pub const UBLK_IO_OP_ZONE_CLOSE: u32 = 11;
+pub const UBLK_IO_OP_ZONE_FINISH: u32 = 12;
+pub const UBLK_IO_OP_ZONE_APPEND: u32 = 13;
+pub const UBLK_IO_OP_ZONE_RESET_ALL: u32 = 14;
+pub const UBLK_IO_OP_ZONE_RESET: u32 = 15;
+/// Construct a zone report. The report request is carried in `struct
+/// ublksrv_io_desc`. The `start_sector` field must be the first sector of a
+/// zone and shall indicate the first zone of the report. The `nr_zones` shall
+/// indicate how many zones should be reported at most. The report shall be
+/// delivered as a `struct blk_zone` array. To report fewer zones than
+/// requested, zero the last entry of the returned array.
+///
+/// Related definitions (blk_zone, blk_zone_cond, blk_zone_type, ...) in
+/// include/uapi/linux/blkzoned.h are part of ublk UAPI.
+pub const UBLK_IO_OP_REPORT_ZONES: u32 = 18;
+
+/// UBLK_F_AUTO_BUF_REG with UBLK_AUTO_BUF_REG_FALLBACK: the buffer couldn't
+/// be registered, the daemon has to do it with UBLK_IO_REGISTER_IO_BUF
+pub const UBLK_IO_F_NEED_REG_BUF: u32 = 1 << 17;
+
+/// io cmd is described by this structure, and stored in share memory, indexed by request tag.
+///
+/// The data is stored by ublk driver, and read by ublksrv after one fetch command returns.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublksrv_io_desc {
+    /// op: bit 0-7, flags: bit 8-31
+    pub op_flags: __u32,
+    /// `nr_zones` for UBLK_IO_OP_REPORT_ZONES, see `ublksrv_io_desc::nr_zones()`
+    pub nr_sectors: __u32,
+    /// start sector for this io
+    pub start_sector: __u64,
+    /// buffer address in ublksrv daemon vm space, from ublk driver
+    pub addr: __u64,
+}
+
+/// issued to ublk driver via /dev/ublkcN
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublksrv_io_cmd {
+    pub q_id: __u16,
+    /// for fetch/commit which result
+    pub tag: __u16,
+    /// io result, it is valid for COMMIT* command only
+    pub result: __s32,
+    /// userspace buffer address in ublksrv daemon process, valid for * FETCH* command only
+    ///
+    /// `zone_append_lba` when committing a UBLK_IO_OP_ZONE_APPEND request,
+    /// see `ublksrv_io_cmd::set_zone_append_lba()`
+    pub addr: __u64,
+}
+
+/// Device numbers of the char and block devices, read only, filled by the
+/// driver for GET_PARAMS
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublk_param_devt {
+    pub char_major: __u32,
+    pub char_minor: __u32,
+    pub disk_major: __u32,
+    pub disk_minor: __u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublk_param_zoned {
+    pub max_open_zones: __u32,
+    pub max_active_zones: __u32,
+    pub max_zone_append_sectors: __u32,
+    pub reserved: [__u8; 20],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublk_param_dma_align {
+    /// alignment mask of the IO buffers' address and length
+    pub alignment: __u32,
+    pub pad: [__u8; 4],
+}
+
+/// smallest `ublk_param_segment::max_segment_size`
+pub const UBLK_MIN_SEGMENT_SIZE: u32 = 4096;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublk_param_segment {
+    pub seg_boundary_mask: __u64,
+    pub max_segment_size: __u32,
+    pub max_segments: __u16,
+    pub pad: [__u8; 2],
+}
+
+pub const UBLK_PARAM_TYPE_DEVT: u32 = 1 << 2;
+pub const UBLK_PARAM_TYPE_ZONED: u32 = 1 << 3;
+pub const UBLK_PARAM_TYPE_DMA_ALIGN: u32 = 1 << 4;
+pub const UBLK_PARAM_TYPE_SEGMENT: u32 = 1 << 5;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublk_params {
+    /// Total length of parameters, userspace has to set 'len' for both
+    /// SET_PARAMS and GET_PARAMS command, and driver may update len
+    /// if two sides use different version of 'ublk_params', same with
+    /// 'types' fields.
+    pub len: __u32,
+    pub types: __u32,
+    pub basic: ublk_param_basic,
+    pub discard: ublk_param_discard,
+    pub devt: ublk_param_devt,
+    pub zoned: ublk_param_zoned,
+    pub dma: ublk_param_dma_align,
+    pub seg: ublk_param_segment,
+}
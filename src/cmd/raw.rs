@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: MIT
+#![allow(dead_code)]
+#![allow(non_camel_case_types)]
+#![allow(clippy::missing_safety_doc)] // FIXME
+use libc::{__s32, __u16, __u32, __u64, __u8};
+
+/// Admin commands, issued by ublk server, and handled by ublk driver.
+pub const UBLK_CMD_GET_QUEUE_AFFINITY: u32 = 1;
+pub const UBLK_CMD_GET_DEV_INFO: u32 = 2;
+pub const UBLK_CMD_ADD_DEV: u32 = 4;
+pub const UBLK_CMD_DEL_DEV: u32 = 5;
+pub const UBLK_CMD_START_DEV: u32 = 6;
+pub const UBLK_CMD_STOP_DEV: u32 = 7;
+pub const UBLK_CMD_SET_PARAMS: u32 = 8;
+pub const UBLK_CMD_GET_PARAMS: u32 = 9;
+pub const UBLK_CMD_START_USER_RECOVERY: u32 = 16;
+pub const UBLK_CMD_END_USER_RECOVERY: u32 = 17;
+/// GET_DEV_INFO for unprivileged devices, the char device path is sent in
+/// front of the info buffer, see `ublksrv_ctrl_cmd::dev_path_len`
+pub const UBLK_CMD_GET_DEV_INFO2: u32 = 18;
+
+/// Length of the buffer of GET_FEATURES, the `UBLK_F_*` flags supported
+/// by the driver
+pub const UBLK_FEATURES_LEN: u32 = 8;
+
+/// IO commands, issued by ublk server, and handled by ublk driver.
+///
+/// FETCH_REQ: issued via sqe(URING_CMD) beforehand for fetching IO request
+///      from ublk driver, should be issued only when starting device. After
+///      the associated cqe is returned, request's tag can be retrieved via
+///      cqe->userdata.
+///
+/// COMMIT_AND_FETCH_REQ: issued via sqe(URING_CMD) after ublkserver handled
+///      this IO request, request's handling result is committed to ublk
+///      driver, meantime FETCH_REQ is piggyback, and FETCH_REQ has to be
+///      handled before completing io request.
+///
+/// NEED_GET_DATA: only used for write requests to set io addr and copy data
+///      When NEED_GET_DATA is set, ublksrv has to issue UBLK_IO_NEED_GET_DATA
+///      command after ublk driver returns UBLK_IO_RES_NEED_GET_DATA.
+///      It is only used if ublksrv set UBLK_F_NEED_GET_DATA flag while starting a ublk device.
+pub const UBLK_IO_FETCH_REQ: u32 = 32;
+pub const UBLK_IO_COMMIT_AND_FETCH_REQ: u32 = 33;
+pub const UBLK_IO_NEED_GET_DATA: u32 = 34;
+
+/// only ABORT means that no re-fetch
+pub const UBLK_IO_RES_OK: u32 = 0;
+pub const UBLK_IO_RES_NEED_GET_DATA: u32 = 1;
+
+pub const UBLKSRV_CMD_BUF_OFFSET: u32 = 0;
+pub const UBLKSRV_IO_BUF_OFFSET: u32 = 0x80000000;
+
+/// Position of a request's data in the char device with `UBLK_F_USER_COPY`,
+/// from `UBLKSRV_IO_BUF_OFFSET`: the byte offset in the request's buffer,
+/// then its tag and its queue id.
+pub const UBLK_IO_BUF_OFF: u32 = 0;
+pub const UBLK_IO_BUF_BITS: u32 = 25;
+
+/// so at most 64K tags for each queue
+pub const UBLK_TAG_OFF: u32 = UBLK_IO_BUF_BITS;
+pub const UBLK_TAG_BITS: u32 = 16;
+
+/// max 4096 queues
+pub const UBLK_QID_OFF: u32 = UBLK_TAG_OFF + UBLK_TAG_BITS;
+pub const UBLK_QID_BITS: u32 = 12;
+
+pub const UBLK_MAX_NR_QUEUES: u32 = 1 << UBLK_QID_BITS;
+
+pub const UBLKSRV_IO_BUF_TOTAL_BITS: u32 = UBLK_QID_OFF + UBLK_QID_BITS;
+
+/// tag bit is 16bit, but the driver limits each queue to 4096 IOs
+pub const UBLK_MAX_QUEUE_DEPTH: u32 = 4096;
+
+/// Zero copy, the request's buffer is registered in the io_uring fixed
+/// buffer table with UBLK_IO_REGISTER_IO_BUF and used there by the target
+pub const UBLK_F_SUPPORT_ZERO_COPY: u32 = 1 << 0;
+
+/// Force to complete io cmd via io_uring_cmd_complete_in_task so that
+/// performance comparison is done easily with using task_work_add
+pub const UBLK_F_URING_CMD_COMP_IN_TASK: u32 = 1 << 1;
+
+/// User should issue io cmd again for write requests to
+/// set io buffer address and copy data from bio vectors
+/// to the userspace io buffer.
+///
+/// In this mode, task_work is not used.
+pub const UBLK_F_NEED_GET_DATA: u32 = 1 << 2;
+
+/// The device survives the exit of its ublksrv daemon: the driver quiesces
+/// the request queue instead of aborting it, until a new daemon attaches
+/// with START_USER_RECOVERY/END_USER_RECOVERY.
+///
+/// IOs that were inflight in the old daemon are requeued, except for the
+/// ones already issued to the backend, which are failed.
+pub const UBLK_F_USER_RECOVERY: u32 = 1 << 3;
+
+/// Same as UBLK_F_USER_RECOVERY, but every inflight IO of the old daemon
+/// is reissued to the new one, so the target must handle duplicated writes.
+pub const UBLK_F_USER_RECOVERY_REISSUE: u32 = 1 << 4;
+
+/// Unprivileged user can create /dev/ublkcN and /dev/ublkbN.
+///
+/// /dev/ublk-control needs to be available for unprivileged user, and it
+/// can be done via udev rule to make all control commands available to
+/// unprivileged user. Except for the command of UBLK_CMD_ADD_DEV, all
+/// other commands are only allowed for the owner of the specified device.
+///
+/// When userspace sends UBLK_CMD_ADD_DEV, the device pair's owner_uid and
+/// owner_gid are stored to ublksrv_ctrl_dev_info by kernel, so far only
+/// the current user's uid/gid is stored, that said owner of the created
+/// device is always the current user.
+///
+/// We still need udev rule to apply OWNER/GROUP with the stored owner_uid
+/// and owner_gid.
+///
+/// Then ublk server can be run as unprivileged user, and /dev/ublkbN can
+/// be accessed and managed by its owner represented by owner_uid/owner_gid.
+pub const UBLK_F_UNPRIVILEGED_DEV: u32 = 1 << 5;
+
+/// The control and IO commands are encoded as ioctls, `_IOWR('u', nr, ...)`,
+/// see the `UBLK_U_*` opcodes.
+pub const UBLK_F_CMD_IOCTL_ENCODE: u32 = 1 << 6;
+
+/// The daemon copies the requests' data itself, with `pread`/`pwrite` on
+/// the char device, instead of the driver copying it to and from
+/// `ublksrv_io_cmd::addr`. See `ublk_user_copy_pos()`.
+pub const UBLK_F_USER_COPY: u32 = 1 << 7;
+
+/// device state
+pub const UBLK_S_DEV_DEAD: u32 = 0;
+pub const UBLK_S_DEV_LIVE: u32 = 1;
+/// the daemon died and the device waits for a new one, see UBLK_F_USER_RECOVERY
+pub const UBLK_S_DEV_QUIESCED: u32 = 2;
+
+/// shipped via sqe->cmd of io_uring command
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublksrv_ctrl_cmd {
+    /// sent to which device, must be valid
+    pub dev_id: __u32,
+    /// sent to which queue, must be -1 if the cmd isn't for queue
+    pub queue_id: __u16,
+    /// cmd specific buffer, can be IN or OUT
+    pub len: __u16,
+    pub addr: __u64,
+    /// inline data
+    pub data: [__u64; 1],
+    /// Used for UBLK_F_UNPRIVILEGED_DEV and UBLK_CMD_GET_DEV_INFO2
+    /// only, include null char
+    pub dev_path_len: __u16,
+    pub pad: __u16,
+    pub reserved: __u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublksrv_ctrl_dev_info {
+    pub nr_hw_queues: __u16,
+    pub queue_depth: __u16,
+    pub state: __u16,
+    pub pad0: __u16,
+    pub max_io_buf_bytes: __u32,
+    pub dev_id: __u32,
+    pub ublksrv_pid: __s32,
+    pub pad1: __u32,
+    pub flags: __u64,
+    /// For ublksrv internal use, invisible to ublk driver
+    pub ublksrv_flags: __u64,
+    /// store by kernel
+    pub owner_uid: __u32,
+    /// store by kernel
+    pub owner_gid: __u32,
+    pub reserved1: __u64,
+    pub reserved2: __u64,
+}
+
+pub const UBLK_IO_OP_READ: u32 = 0;
+pub const UBLK_IO_OP_WRITE: u32 = 1;
+pub const UBLK_IO_OP_FLUSH: u32 = 2;
+pub const UBLK_IO_OP_DISCARD: u32 = 3;
+pub const UBLK_IO_OP_WRITE_SAME: u32 = 4;
+pub const UBLK_IO_OP_WRITE_ZEROES: u32 = 5;
+
+pub const UBLK_IO_F_FAILFAST_DEV: u32 = 1 << 8;
+pub const UBLK_IO_F_FAILFAST_TRANSPORT: u32 = 1 << 9;
+pub const UBLK_IO_F_FAILFAST_DRIVER: u32 = 1 << 10;
+pub const UBLK_IO_F_META: u32 = 1 << 11;
+pub const UBLK_IO_F_FUA: u32 = 1 << 13;
+pub const UBLK_IO_F_NOUNMAP: u32 = 1 << 15;
+pub const UBLK_IO_F_SWAP: u32 = 1 << 16;
+
+pub const UBLK_ATTR_READ_ONLY: u32 = 1 << 0;
+pub const UBLK_ATTR_ROTATIONAL: u32 = 1 << 1;
+pub const UBLK_ATTR_VOLATILE_CACHE: u32 = 1 << 2;
+pub const UBLK_ATTR_FUA: u32 = 1 << 3;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublk_param_basic {
+    pub attrs: __u32,
+    pub logical_bs_shift: __u8,
+    pub physical_bs_shift: __u8,
+    pub io_opt_shift: __u8,
+    pub io_min_shift: __u8,
+    pub max_sectors: __u32,
+    pub chunk_sectors: __u32,
+    pub dev_sectors: __u64,
+    pub virt_boundary_mask: __u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublk_param_discard {
+    pub discard_alignment: __u32,
+    pub discard_granularity: __u32,
+    pub max_discard_sectors: __u32,
+    pub max_write_zeroes_sectors: __u32,
+    pub max_discard_segments: __u16,
+    pub reserved0: __u16,
+}
+
+pub const UBLK_PARAM_TYPE_BASIC: u32 = 1 << 0;
+pub const UBLK_PARAM_TYPE_DISCARD: u32 = 1 << 1;
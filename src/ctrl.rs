@@ -0,0 +1,455 @@
+// SPDX-License-Identifier: MIT
+//! Control commands sent to `/dev/ublk-control` directly, without
+//! libublksrv.
+//!
+//! A command is a `cmd::ublksrv_ctrl_cmd` in the payload of an
+//! `IORING_OP_URING_CMD` of an SQE128 ring, see `Control`.
+
+use crate::cmd::{self, ublk_params, ublksrv_ctrl_cmd, ublksrv_ctrl_dev_info, UnprivCtrlBuf};
+use crate::error::{Error, Op, Result, ENOTSUPP};
+use crate::uring::{Ring, IORING_SETUP_SQE128};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::fd::AsRawFd;
+
+/// Path of the ublk control device
+pub const UBLK_CONTROL_PATH: &str = "/dev/ublk-control";
+
+/// `UBLK_F_*` flags supported by the running kernel, see `get_features()`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Features(u64);
+
+impl Features {
+    /// What the kernels without `UBLK_U_CMD_GET_FEATURES` (before 6.5) are
+    /// known to support. Newer flags may still work there, but can only be
+    /// found out by adding a device.
+    pub const LEGACY: Features =
+        Features((cmd::UBLK_F_URING_CMD_COMP_IN_TASK | cmd::UBLK_F_NEED_GET_DATA) as u64);
+
+    pub const fn from_bits(flags: u64) -> Self {
+        Features(flags)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Whether all of the `UBLK_F_*` `flags` are supported
+    pub const fn contains(&self, flags: u64) -> bool {
+        self.0 & flags == flags
+    }
+
+    pub const fn supports_zero_copy(&self) -> bool {
+        self.contains(cmd::UBLK_F_SUPPORT_ZERO_COPY as u64)
+    }
+
+    pub const fn supports_need_get_data(&self) -> bool {
+        self.contains(cmd::UBLK_F_NEED_GET_DATA as u64)
+    }
+
+    pub const fn supports_user_recovery(&self) -> bool {
+        self.contains(cmd::UBLK_F_USER_RECOVERY as u64)
+    }
+
+    pub const fn supports_user_recovery_reissue(&self) -> bool {
+        self.contains(cmd::UBLK_F_USER_RECOVERY_REISSUE as u64)
+    }
+
+    pub const fn supports_unprivileged_dev(&self) -> bool {
+        self.contains(cmd::UBLK_F_UNPRIVILEGED_DEV as u64)
+    }
+
+    pub const fn supports_ioctl_encode(&self) -> bool {
+        self.contains(cmd::UBLK_F_CMD_IOCTL_ENCODE as u64)
+    }
+
+    pub const fn supports_user_copy(&self) -> bool {
+        self.contains(cmd::UBLK_F_USER_COPY as u64)
+    }
+
+    pub const fn supports_zoned(&self) -> bool {
+        self.contains(cmd::UBLK_F_ZONED as u64)
+    }
+
+    pub const fn supports_update_size(&self) -> bool {
+        self.contains(cmd::UBLK_F_UPDATE_SIZE as u64)
+    }
+
+    pub const fn supports_auto_buf_reg(&self) -> bool {
+        self.contains(cmd::UBLK_F_AUTO_BUF_REG as u64)
+    }
+
+    pub const fn supports_quiesce(&self) -> bool {
+        self.contains(cmd::UBLK_F_QUIESCE as u64)
+    }
+
+    pub const fn supports_per_io_daemon(&self) -> bool {
+        self.contains(cmd::UBLK_F_PER_IO_DAEMON as u64)
+    }
+}
+
+/// Bytes of a plain C struct, to be read or written by the driver
+fn struct_bytes<T: Copy>(v: &mut T) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(v as *mut T as *mut u8, size_of::<T>()) }
+}
+
+/// Error of command `op` for device `dev_id`
+fn dev_err(op: Op, dev_id: u32) -> impl FnOnce(io::Error) -> Error {
+    move |e| Error::new(op, e).with_dev(dev_id)
+}
+
+/// `/dev/ublk-control`, with the ring its commands are sent on.
+///
+/// The device commands take the id and the `UBLK_F_*` flags of the device:
+/// with `UBLK_F_CMD_IOCTL_ENCODE` they are sent with their `UBLK_U_CMD_*`
+/// opcode, and with `UBLK_F_UNPRIVILEGED_DEV` the path of the char device
+/// goes with them (see `cmd::UnprivCtrlBuf`). The commands added after the
+/// ioctl encoding are always sent encoded.
+pub struct Control {
+    ctrl: File,
+    ring: Ring,
+    /// user_data of the last command
+    seq: u64,
+}
+
+impl Control {
+    pub fn open() -> Result<Self> {
+        let ctrl = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(UBLK_CONTROL_PATH)
+            .map_err(|e| Error::new(Op::OpenControl, e))?;
+        let ring = Ring::new(1, IORING_SETUP_SQE128).map_err(|e| Error::new(Op::OpenControl, e))?;
+        Ok(Control { ctrl, ring, seq: 0 })
+    }
+
+    /// Sends `cmd` with opcode `cmd_op`, returns the (non negative) result
+    /// of the command.
+    ///
+    /// Once submitted, the command may write to its buffer until it
+    /// completes, which is told from others by its user_data. The driver
+    /// is so given a copy of the buffer, copied back on completion: if
+    /// waiting for the completion fails, the copy is leaked and the error
+    /// returned.
+    fn cmd(&mut self, cmd_op: u32, cmd: &ublksrv_ctrl_cmd) -> io::Result<i32> {
+        let mut sent = *cmd;
+        let mut buf = match (cmd.addr, cmd.len) {
+            (0, _) | (_, 0) => Vec::new(),
+            (addr, len) => {
+                unsafe { std::slice::from_raw_parts(addr as *const u8, len as usize) }.to_vec()
+            }
+        };
+        if !buf.is_empty() {
+            sent.addr = buf.as_mut_ptr() as u64;
+        }
+
+        let payload = unsafe {
+            std::slice::from_raw_parts(
+                &sent as *const ublksrv_ctrl_cmd as *const u8,
+                size_of::<ublksrv_ctrl_cmd>(),
+            )
+        };
+
+        self.seq = self.seq.wrapping_add(1);
+        let user_data = self.seq;
+        self.ring
+            .prep_uring_cmd(self.ctrl.as_raw_fd(), cmd_op, payload, user_data)?;
+        // not in flight if this fails
+        self.ring.submit_and_wait(1)?;
+
+        loop {
+            while let Some(cqe) = self.ring.pop_cqe() {
+                if cqe.user_data != user_data {
+                    continue;
+                }
+                if cqe.res < 0 {
+                    return Err(io::Error::from_raw_os_error(-cqe.res));
+                }
+                if !buf.is_empty() {
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(buf.as_ptr(), cmd.addr as *mut u8, buf.len())
+                    };
+                }
+                return Ok(cqe.res);
+            }
+
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EBUSY)) => {}
+                // the command may still write to the copy
+                Err(e) => {
+                    std::mem::forget(buf);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Command for device `dev_id`, with the char device path in its
+    /// buffer if `dev_flags` has `UBLK_F_UNPRIVILEGED_DEV`.
+    fn dev_cmd(&mut self, dev_id: u32, dev_flags: u64, cmd_op: u32, data: u64) -> io::Result<i32> {
+        let mut cmd = ublksrv_ctrl_cmd {
+            dev_id,
+            queue_id: u16::MAX,
+            data: [data],
+            ..Default::default()
+        };
+
+        let mut buf = None;
+        if dev_flags & cmd::UBLK_F_UNPRIVILEGED_DEV as u64 != 0 {
+            buf.insert(UnprivCtrlBuf::for_dev(dev_id, 0)?)
+                .fill_cmd(&mut cmd);
+        }
+
+        self.cmd(cmd_op, &cmd)
+    }
+
+    /// Same as `dev_cmd()`, with `buf` as the command's buffer, which the
+    /// driver reads from or writes to.
+    fn dev_buf_cmd(
+        &mut self,
+        dev_id: u32,
+        dev_flags: u64,
+        cmd_op: u32,
+        data: u64,
+        buf: &mut [u8],
+    ) -> io::Result<i32> {
+        let mut cmd = ublksrv_ctrl_cmd {
+            dev_id,
+            queue_id: u16::MAX,
+            data: [data],
+            ..Default::default()
+        };
+
+        if dev_flags & cmd::UBLK_F_UNPRIVILEGED_DEV as u64 == 0 {
+            cmd.addr = buf.as_mut_ptr() as u64;
+            cmd.len = u16::try_from(buf.len())
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+            return self.cmd(cmd_op, &cmd);
+        }
+
+        let mut unpriv = UnprivCtrlBuf::for_dev(dev_id, buf.len())?;
+        unpriv.payload_mut().copy_from_slice(buf);
+        unpriv.fill_cmd(&mut cmd);
+        let ret = self.cmd(cmd_op, &cmd)?;
+        buf.copy_from_slice(unpriv.payload());
+        Ok(ret)
+    }
+
+    /// Asks the kernel which `UBLK_F_*` flags it supports.
+    ///
+    /// Kernels that predate `UBLK_U_CMD_GET_FEATURES` reject it, for them
+    /// `Features::LEGACY` is returned. Before 6.5 the driver looks up the
+    /// device of every command other than ADD_DEV first, so the command
+    /// fails there with `ENODEV`, as `dev_id` is `u32::MAX`.
+    pub fn get_features(&mut self) -> Result<Features> {
+        let mut features: u64 = 0;
+        let cmd = ublksrv_ctrl_cmd {
+            dev_id: u32::MAX,
+            queue_id: u16::MAX,
+            len: cmd::UBLK_FEATURES_LEN as u16,
+            addr: &mut features as *mut u64 as u64,
+            ..Default::default()
+        };
+
+        match self.cmd(cmd::UBLK_U_CMD_GET_FEATURES, &cmd) {
+            Ok(_) => Ok(Features(features)),
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EINVAL | libc::ENODEV | libc::EOPNOTSUPP | ENOTSUPP)
+                ) =>
+            {
+                Ok(Features::LEGACY)
+            }
+            Err(e) => Err(Error::new(Op::GetFeatures, e)),
+        }
+    }
+
+    /// Adds the device described by `info`, with the id `info.dev_id`, or
+    /// the first free one if it is `u32::MAX`.
+    ///
+    /// `info` is updated with what the driver filled in: the id, the flags
+    /// it kept and the owner. The device is then in `UBLK_S_DEV_DEAD` until
+    /// it is started.
+    pub fn add_dev(&mut self, info: &mut ublksrv_ctrl_dev_info) -> Result<u32> {
+        let cmd = ublksrv_ctrl_cmd {
+            dev_id: info.dev_id,
+            queue_id: u16::MAX,
+            len: size_of::<ublksrv_ctrl_dev_info>() as u16,
+            addr: info as *mut ublksrv_ctrl_dev_info as u64,
+            ..Default::default()
+        };
+
+        self.cmd(
+            cmd::ublk_ctrl_cmd_op(info.flags, cmd::UBLK_CMD_ADD_DEV),
+            &cmd,
+        )
+        .map_err(|e| {
+            let err = Error::new(Op::AddDev, e);
+            match cmd.dev_id {
+                u32::MAX => err,
+                dev_id => err.with_dev(dev_id),
+            }
+        })?;
+        Ok(info.dev_id)
+    }
+
+    /// Removes the device, waits until it isn't used anymore.
+    pub fn del_dev(&mut self, dev_id: u32, dev_flags: u64) -> Result<()> {
+        let cmd_op = cmd::ublk_ctrl_cmd_op(dev_flags, cmd::UBLK_CMD_DEL_DEV);
+        self.dev_cmd(dev_id, dev_flags, cmd_op, 0)
+            .map_err(dev_err(Op::DelDev, dev_id))?;
+        Ok(())
+    }
+
+    /// Starts the device, served by the process `daemon_pid`.
+    ///
+    /// Completes once every queue has fetched all its tags, so the queues
+    /// have to be run by other threads.
+    pub fn start_dev(&mut self, dev_id: u32, dev_flags: u64, daemon_pid: i32) -> Result<()> {
+        let cmd_op = cmd::ublk_ctrl_cmd_op(dev_flags, cmd::UBLK_CMD_START_DEV);
+        self.dev_cmd(dev_id, dev_flags, cmd_op, daemon_pid as u64)
+            .map_err(dev_err(Op::StartDev, dev_id))?;
+        Ok(())
+    }
+
+    /// Stops the device, its block device is removed and the fetch
+    /// commands of its queues complete with `UBLK_IO_RES_ABORT`.
+    pub fn stop_dev(&mut self, dev_id: u32, dev_flags: u64) -> Result<()> {
+        let cmd_op = cmd::ublk_ctrl_cmd_op(dev_flags, cmd::UBLK_CMD_STOP_DEV);
+        self.dev_cmd(dev_id, dev_flags, cmd_op, 0)
+            .map_err(dev_err(Op::StopDev, dev_id))?;
+        Ok(())
+    }
+
+    /// Reads the info of the device, with `UBLK_CMD_GET_DEV_INFO2` for an
+    /// unprivileged one, as libublksrv does.
+    pub fn get_info(&mut self, dev_id: u32, dev_flags: u64) -> Result<ublksrv_ctrl_dev_info> {
+        let op = if dev_flags & cmd::UBLK_F_UNPRIVILEGED_DEV as u64 != 0 {
+            cmd::UBLK_CMD_GET_DEV_INFO2
+        } else {
+            cmd::UBLK_CMD_GET_DEV_INFO
+        };
+
+        let mut info = ublksrv_ctrl_dev_info::default();
+        let cmd_op = cmd::ublk_ctrl_cmd_op(dev_flags, op);
+        self.dev_buf_cmd(dev_id, dev_flags, cmd_op, 0, struct_bytes(&mut info))
+            .map_err(dev_err(Op::GetInfo, dev_id))?;
+        Ok(info)
+    }
+
+    /// Reads the params of the device.
+    ///
+    /// The struct known here is offered, a driver with a smaller one copies
+    /// less and only what it reported is read, see
+    /// `ublk_params::from_driver()`.
+    pub fn get_params(&mut self, dev_id: u32, dev_flags: u64) -> Result<ublk_params> {
+        let mut buf = ublk_params::new().to_bytes();
+        let cmd_op = cmd::ublk_ctrl_cmd_op(dev_flags, cmd::UBLK_CMD_GET_PARAMS);
+        self.dev_buf_cmd(dev_id, dev_flags, cmd_op, 0, &mut buf)
+            .map_err(dev_err(Op::GetParams, dev_id))?;
+        Ok(ublk_params::from_driver(&buf))
+    }
+
+    /// Sets the params of the device, before it is started.
+    ///
+    /// `params` is sent whole, with its `len` set to the size of the struct
+    /// known here, the driver ignores what is beyond its own struct. The
+    /// params are read back and the `UBLK_PARAM_TYPE_*` groups of `params`
+    /// that took effect are returned, a driver too old for a group doesn't
+    /// fail the command but drops it.
+    pub fn set_params(&mut self, dev_id: u32, dev_flags: u64, params: &ublk_params) -> Result<u32> {
+        let mut params = *params;
+        params.len = size_of::<ublk_params>() as u32;
+
+        let mut buf = params.to_bytes();
+        let cmd_op = cmd::ublk_ctrl_cmd_op(dev_flags, cmd::UBLK_CMD_SET_PARAMS);
+        self.dev_buf_cmd(dev_id, dev_flags, cmd_op, 0, &mut buf)
+            .map_err(dev_err(Op::SetParams, dev_id))?;
+
+        let applied = self.get_params(dev_id, dev_flags)?;
+        Ok(params.types & applied.types)
+    }
+
+    /// CPUs the driver assigned to queue `q_id` of the device
+    pub fn get_queue_affinity(
+        &mut self,
+        dev_id: u32,
+        dev_flags: u64,
+        q_id: u16,
+    ) -> Result<libc::cpu_set_t> {
+        let mut cpus: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let cmd_op = cmd::ublk_ctrl_cmd_op(dev_flags, cmd::UBLK_CMD_GET_QUEUE_AFFINITY);
+        self.dev_buf_cmd(
+            dev_id,
+            dev_flags,
+            cmd_op,
+            q_id as u64,
+            struct_bytes(&mut cpus),
+        )
+        .map_err(|e| {
+            Error::new(Op::GetAffinity, e)
+                .with_dev(dev_id)
+                .with_queue(q_id)
+        })?;
+        Ok(cpus)
+    }
+
+    /// Changes the size of the live device to `dev_sectors` sectors of 512
+    /// bytes.
+    ///
+    /// Needs `UBLK_F_UPDATE_SIZE` support, the driver updates its
+    /// `ublk_param_basic::dev_sectors` and the capacity of the block device.
+    pub fn update_size(&mut self, dev_id: u32, dev_flags: u64, dev_sectors: u64) -> Result<()> {
+        self.dev_cmd(dev_id, dev_flags, cmd::UBLK_U_CMD_UPDATE_SIZE, dev_sectors)
+            .map_err(dev_err(Op::UpdateSize, dev_id))?;
+        Ok(())
+    }
+
+    /// Quiesces the live device: the driver waits up to `timeout_ms` for
+    /// the inflight requests, stops delivering new ones and aborts the
+    /// queues' commands. The device then stays in `UBLK_S_DEV_QUIESCED`
+    /// until it is recovered.
+    ///
+    /// Needs `UBLK_F_QUIESCE` and `UBLK_F_USER_RECOVERY`.
+    pub fn quiesce_dev(&mut self, dev_id: u32, dev_flags: u64, timeout_ms: u32) -> Result<()> {
+        self.dev_cmd(
+            dev_id,
+            dev_flags,
+            cmd::UBLK_U_CMD_QUIESCE_DEV,
+            timeout_ms as u64,
+        )
+        .map_err(dev_err(Op::QuiesceDev, dev_id))?;
+        Ok(())
+    }
+}
+
+/// Same as `Control::get_features()`, fails if the ublk driver isn't
+/// loaded.
+pub fn get_features() -> Result<Features> {
+    Control::open()?.get_features()
+}
+
+/// Same as `Control::update_size()`, for device `dev_id` whose flags are
+/// `dev_flags`
+pub fn update_size(dev_id: u32, dev_flags: u64, dev_sectors: u64) -> Result<()> {
+    Control::open()?.update_size(dev_id, dev_flags, dev_sectors)
+}
+
+/// Same as `Control::quiesce_dev()`, for device `dev_id` whose flags are
+/// `dev_flags`
+pub fn quiesce_dev(dev_id: u32, dev_flags: u64, timeout_ms: u32) -> Result<()> {
+    Control::open()?.quiesce_dev(dev_id, dev_flags, timeout_ms)
+}
+
+/// Same as `Control::get_params()`
+pub fn get_params(dev_id: u32, dev_flags: u64) -> Result<ublk_params> {
+    Control::open()?.get_params(dev_id, dev_flags)
+}
+
+/// Same as `Control::set_params()`
+pub fn set_params(dev_id: u32, dev_flags: u64, params: &ublk_params) -> Result<u32> {
+    Control::open()?.set_params(dev_id, dev_flags, params)
+}
@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: MIT
+//! Errors of the safe wrappers, with the operation and the device or queue
+//! they were about.
+
+use std::fmt;
+use std::io;
+
+/// The kernel's own ENOTSUPP, returned for unknown commands
+pub(crate) const ENOTSUPP: i32 = 524;
+
+/// Operation that failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Op {
+    /// Checking the device data, see `srv::DevDataBuilder`
+    DevData,
+    /// Opening `/dev/ublk-control`, or `ublksrv_ctrl_init`
+    OpenControl,
+    GetFeatures,
+    AddDev,
+    DelDev,
+    StartDev,
+    StopDev,
+    GetInfo,
+    SetParams,
+    GetParams,
+    GetAffinity,
+    UpdateSize,
+    QuiesceDev,
+    StartRecovery,
+    EndRecovery,
+    /// `ublksrv_dev_init`
+    DevInit,
+    /// `ublksrv_queue_init`, or setting up a native queue, see `queue::Queue`
+    QueueInit,
+    /// Fetching and committing the requests of a queue
+    ProcessIo,
+    JsonReadParams,
+    JsonWriteParams,
+}
+
+impl Op {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Op::DevData => "check device data",
+            Op::OpenControl => "open control device",
+            Op::GetFeatures => "get features",
+            Op::AddDev => "add device",
+            Op::DelDev => "delete device",
+            Op::StartDev => "start device",
+            Op::StopDev => "stop device",
+            Op::GetInfo => "get device info",
+            Op::SetParams => "set params",
+            Op::GetParams => "get params",
+            Op::GetAffinity => "get queue affinity",
+            Op::UpdateSize => "update size",
+            Op::QuiesceDev => "quiesce device",
+            Op::StartRecovery => "start recovery",
+            Op::EndRecovery => "end recovery",
+            Op::DevInit => "init device",
+            Op::QueueInit => "init queue",
+            Op::ProcessIo => "process io",
+            Op::JsonReadParams => "read json params",
+            Op::JsonWriteParams => "write json params",
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What an error means for the operation it comes from, see `Error::kind()`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The ublk driver isn't loaded, `/dev/ublk-control` is missing or has
+    /// no driver behind it
+    DriverNotLoaded,
+    /// `EEXIST` when adding a device: its id is already in use
+    DevIdInUse,
+    /// `ENODEV` for a device command: there is no such device
+    NoSuchDevice,
+    /// The caller may not manage the device, e.g. an unprivileged one owned
+    /// by someone else
+    PermissionDenied,
+    /// The driver doesn't know the command or one of the flags
+    NotSupported,
+    /// The device is busy, e.g. still started or opened
+    Busy,
+    Other,
+}
+
+/// Error of an operation on the ublk driver or libublksrv
+#[derive(Debug)]
+pub struct Error {
+    op: Op,
+    dev_id: Option<u32>,
+    q_id: Option<u16>,
+    source: io::Error,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(op: Op, source: io::Error) -> Self {
+        Error {
+            op,
+            dev_id: None,
+            q_id: None,
+            source,
+        }
+    }
+
+    /// Error of a libublksrv or driver call that returned the negative
+    /// errno `ret`
+    pub fn from_ret(op: Op, ret: i32) -> Self {
+        Self::new(op, io::Error::from_raw_os_error(-ret))
+    }
+
+    /// Checks the return value of a libublksrv call, a negative errno is
+    /// an error
+    pub fn check(op: Op, ret: i32) -> Result<i32> {
+        if ret < 0 {
+            return Err(Self::from_ret(op, ret));
+        }
+        Ok(ret)
+    }
+
+    /// The error was about device `dev_id`
+    pub fn with_dev(mut self, dev_id: u32) -> Self {
+        self.dev_id = Some(dev_id);
+        self
+    }
+
+    /// The error was about queue `q_id`
+    pub fn with_queue(mut self, q_id: u16) -> Self {
+        self.q_id = Some(q_id);
+        self
+    }
+
+    pub fn op(&self) -> Op {
+        self.op
+    }
+
+    pub fn dev_id(&self) -> Option<u32> {
+        self.dev_id
+    }
+
+    pub fn queue_id(&self) -> Option<u16> {
+        self.q_id
+    }
+
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+
+    pub fn into_io_error(self) -> io::Error {
+        self.source
+    }
+
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+
+    /// The errno, as a negative return value of the C API, `-EIO` if the
+    /// error doesn't have one
+    pub fn to_ret(&self) -> i32 {
+        -self.raw_os_error().unwrap_or(libc::EIO)
+    }
+
+    /// What the errno means for the operation
+    pub fn kind(&self) -> ErrorKind {
+        let Some(errno) = self.raw_os_error() else {
+            return ErrorKind::Other;
+        };
+
+        match (self.op, errno) {
+            (Op::OpenControl, libc::ENOENT | libc::ENODEV | libc::ENXIO) => {
+                ErrorKind::DriverNotLoaded
+            }
+            (Op::AddDev, libc::EEXIST) => ErrorKind::DevIdInUse,
+            (_, libc::ENODEV) => ErrorKind::NoSuchDevice,
+            (_, libc::EPERM | libc::EACCES) => ErrorKind::PermissionDenied,
+            (_, libc::EOPNOTSUPP | ENOTSUPP) => ErrorKind::NotSupported,
+            (_, libc::EBUSY) => ErrorKind::Busy,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ublk: {} failed", self.op)?;
+        if let Some(dev_id) = self.dev_id {
+            write!(f, " for device {dev_id}")?;
+        }
+        if let Some(q_id) = self.q_id {
+            write!(f, " queue {q_id}")?;
+        }
+
+        match self.kind() {
+            ErrorKind::DriverNotLoaded => f.write_str(": the ublk_drv module isn't loaded")?,
+            ErrorKind::DevIdInUse => f.write_str(": the device id is already in use")?,
+            ErrorKind::NoSuchDevice => f.write_str(": no such device")?,
+            _ => {}
+        }
+        write!(f, " ({})", self.source)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(err.source.kind(), err)
+    }
+}
@@ -1,142 +1,211 @@
 // SPDX-License-Identifier: MIT
-#![allow(non_camel_case_types)]
-use crate::{__IncompleteArrayField, d};
-use libc::{__s32, __u16, __u32, __u64, __u8, c_int, c_uint, c_void, size_t};
 
-/// Minimal liburing 2.2 bindings
-type __kernel_rwf_t = c_int; // linux/fs.h
+#[cfg(not(feature = "bindgen"))]
+mod raw;
+/// Generated from `liburing.h` at build time
+#[cfg(feature = "bindgen")]
+mod raw {
+    #![allow(non_camel_case_types, non_upper_case_globals, dead_code)]
+    use libc::{__s32, __u16, __u32, __u64, __u8};
+    type __kernel_rwf_t = ::std::os::raw::c_int; // linux/fs.h
+    include!(concat!(env!("OUT_DIR"), "/liburing.rs"));
+}
+pub use raw::*;
+
+use crate::uring::{self, Sqe};
+use libc::{c_int, c_uint, c_void};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub const IOSQE_FIXED_FILE: u8 = 1 << 0;
+pub const IOSQE_IO_DRAIN: u8 = 1 << 1;
+pub const IOSQE_IO_LINK: u8 = 1 << 2;
+pub const IOSQE_IO_HARDLINK: u8 = 1 << 3;
+pub const IOSQE_ASYNC: u8 = 1 << 4;
+pub const IOSQE_BUFFER_SELECT: u8 = 1 << 5;
+pub const IOSQE_CQE_SKIP_SUCCESS: u8 = 1 << 6;
+
+pub const IORING_SETUP_SQE128: c_uint = uring::IORING_SETUP_SQE128;
+
+const IORING_REGISTER_BUFFERS2: c_uint = 15;
+const IORING_RSRC_REGISTER_SPARSE: u32 = 1 << 0;
 
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct io_uring {
-    pub sq: io_uring_sq,
-    pub cq: io_uring_cq,
-    pub flags: c_uint,
-    pub ring_fd: c_int,
-    pub features: c_uint,
-    pub enter_ring_fd: c_int,
-    pub int_flags: __u8,
-    pub pad: [__u8; 3],
-    pub pad2: c_uint,
+#[derive(Debug, Default, Copy, Clone)]
+struct io_uring_rsrc_register {
+    nr: u32,
+    flags: u32,
+    resv2: u64,
+    data: u64,
+    tags: u64,
 }
-d!(io_uring);
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct io_uring_cqe {
-    pub user_data: __u64,
-    pub res: __s32,
-    pub flags: __u32,
-    pub big_cqe: __IncompleteArrayField<__u64>,
+const _: () = assert!(std::mem::size_of::<io_uring_sqe>() == uring::SQE_SIZE);
+
+unsafe fn sq_mask_entries(sq: &io_uring_sq) -> (c_uint, c_uint) {
+    #[cfg(liburing_2_3)]
+    return (sq.ring_mask, sq.ring_entries);
+    #[cfg(not(liburing_2_3))]
+    return (*sq.kring_mask, *sq.kring_entries);
 }
-d!(io_uring_cqe);
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct io_uring_sq {
-    pub khead: *mut c_uint,
-    pub ktail: *mut c_uint,
-    pub kring_mask: *mut c_uint,
-    pub kring_entries: *mut c_uint,
-    pub kflags: *mut c_uint,
-    pub kdropped: *mut c_uint,
-    pub array: *mut c_uint,
-    pub sqes: *mut io_uring_sqe,
-    pub sqe_head: c_uint,
-    pub sqe_tail: c_uint,
-    pub ring_sz: size_t,
-    pub ring_ptr: *mut c_void,
-    pub pad: [c_uint; 4],
+/// Number of sqes that can still be taken with `io_uring_get_sqe()`
+pub unsafe fn io_uring_sq_space_left(ring: *const io_uring) -> c_uint {
+    let sq = &(*ring).sq;
+    let (_, entries) = sq_mask_entries(sq);
+    let head = (*(sq.khead as *const AtomicU32)).load(Ordering::Acquire);
+    entries - sq.sqe_tail.wrapping_sub(head)
 }
-d!(io_uring_sq);
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct io_uring_cq {
-    pub khead: *mut c_uint,
-    pub ktail: *mut c_uint,
-    pub kring_mask: *mut c_uint,
-    pub kring_entries: *mut c_uint,
-    pub kflags: *mut c_uint,
-    pub koverflow: *mut c_uint,
-    pub cqes: *mut io_uring_cqe,
-    pub ring_sz: size_t,
-    pub ring_ptr: *mut c_void,
-    pub pad: [c_uint; 4],
+/// Same as liburing's `io_uring_get_sqe`, which is inline in its recent
+/// versions. Returns null if the sq ring is full.
+pub unsafe fn io_uring_get_sqe(ring: *mut io_uring) -> *mut io_uring_sqe {
+    if io_uring_sq_space_left(ring) == 0 {
+        return std::ptr::null_mut();
+    }
+
+    let sq = &mut (*ring).sq;
+    let (mask, _) = sq_mask_entries(sq);
+    let shift = ((*ring).flags & IORING_SETUP_SQE128 != 0) as u32;
+    let sqe = sq.sqes.add(((sq.sqe_tail & mask) << shift) as usize);
+    sq.sqe_tail = sq.sqe_tail.wrapping_add(1);
+    sqe
 }
-d!(io_uring_cq);
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct io_uring_sqe {
-    pub opcode: __u8,
-    pub flags: __u8,
-    pub ioprio: __u16,
-    pub fd: __s32,
-    pub u1: io_uring_sqe_union_1_ty,
-    pub u2: io_uring_sqe_union_2_ty,
-    pub len: __u32,
-    pub u3: io_uring_sqe_union_3_ty,
-    pub user_data: __u64,
-    pub u4: io_uring_sqe_union_4_ty,
-    pub personality: __u16,
-    pub u5: io_uring_sqe_union_5_ty,
-    pub addr3: __u64,
-    pub __pad2: [__u64; 1usize],
+unsafe fn prep_rw_fixed(
+    sqe: *mut io_uring_sqe,
+    opcode: u8,
+    fd: c_int,
+    buf: *const c_void,
+    nbytes: c_uint,
+    offset: u64,
+    buf_index: c_int,
+) {
+    std::ptr::write(
+        sqe as *mut Sqe,
+        Sqe {
+            opcode,
+            fd,
+            off: offset,
+            addr: buf as u64,
+            len: nbytes,
+            buf_index: buf_index as u16,
+            ..Default::default()
+        },
+    );
 }
-d!(io_uring_sqe);
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub union io_uring_sqe_union_1_ty {
-    pub off: __u64,
-    pub addr2: __u64,
+/// Same as liburing's `io_uring_prep_read_fixed`: reads `nbytes` at
+/// `offset` of `fd` into `buf`, which is in the registered buffer
+/// `buf_index`. For a buffer registered by the ublk driver, `buf` is the
+/// offset in the request's data.
+pub unsafe fn io_uring_prep_read_fixed(
+    sqe: *mut io_uring_sqe,
+    fd: c_int,
+    buf: *mut c_void,
+    nbytes: c_uint,
+    offset: u64,
+    buf_index: c_int,
+) {
+    prep_rw_fixed(
+        sqe,
+        uring::IORING_OP_READ_FIXED,
+        fd,
+        buf,
+        nbytes,
+        offset,
+        buf_index,
+    );
 }
-d!(io_uring_sqe_union_1_ty);
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub union io_uring_sqe_union_2_ty {
-    pub addr: __u64,
-    pub splice_off_in: __u64,
+/// Same as `io_uring_prep_read_fixed()`, for writing `buf` to `fd`
+pub unsafe fn io_uring_prep_write_fixed(
+    sqe: *mut io_uring_sqe,
+    fd: c_int,
+    buf: *const c_void,
+    nbytes: c_uint,
+    offset: u64,
+    buf_index: c_int,
+) {
+    prep_rw_fixed(
+        sqe,
+        uring::IORING_OP_WRITE_FIXED,
+        fd,
+        buf,
+        nbytes,
+        offset,
+        buf_index,
+    );
 }
-d!(io_uring_sqe_union_2_ty);
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub union io_uring_sqe_union_3_ty {
-    pub rw_flags: __kernel_rwf_t,
-    pub fsync_flags: __u32,
-    pub poll_events: __u16,
-    pub poll32_events: __u32,
-    pub sync_range_flags: __u32,
-    pub msg_flags: __u32,
-    pub timeout_flags: __u32,
-    pub accept_flags: __u32,
-    pub cancel_flags: __u32,
-    pub open_flags: __u32,
-    pub statx_flags: __u32,
-    pub fadvise_advice: __u32,
-    pub splice_flags: __u32,
-    pub rename_flags: __u32,
-    pub unlink_flags: __u32,
-    pub hardlink_flags: __u32,
-    pub xattr_flags: __u32,
+/// Same as liburing's `io_uring_register_buffers_sparse`: sets up a fixed
+/// buffer table of `nr` empty entries, for buffers registered later, e.g.
+/// with `UBLK_U_IO_REGISTER_IO_BUF`.
+///
+/// Returns 0 on success or a negative errno.
+pub unsafe fn io_uring_register_buffers_sparse(ring: *mut io_uring, nr: c_uint) -> c_int {
+    let reg = io_uring_rsrc_register {
+        nr,
+        flags: IORING_RSRC_REGISTER_SPARSE,
+        ..Default::default()
+    };
+    let ret = libc::syscall(
+        libc::SYS_io_uring_register,
+        (*ring).ring_fd,
+        IORING_REGISTER_BUFFERS2,
+        &reg as *const io_uring_rsrc_register,
+        std::mem::size_of::<io_uring_rsrc_register>(),
+    );
+    if ret < 0 {
+        return -std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO);
+    }
+    0
 }
-d!(io_uring_sqe_union_3_ty);
 
-#[repr(C, packed)]
-#[derive(Copy, Clone)]
-pub union io_uring_sqe_union_4_ty {
-    pub buf_index: __u16,
-    pub buf_group: __u16,
+pub(crate) fn fd_is_open(fd: libc::c_int) -> bool {
+    fd >= 0 && unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1
 }
-d!(io_uring_sqe_union_4_ty);
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub union io_uring_sqe_union_5_ty {
-    pub splice_fd_in: __s32,
-    pub file_index: __u32,
+/// Sanity check of an `io_uring` set up by liburing, against the layout
+/// this crate was built for.
+///
+/// `io_uring` is embedded by value in libublksrv's structs, so a layout
+/// mismatch with the linked liburing shifts every field after it. The kernel
+/// ring pointers come first in every layout, the fields read through them
+/// are cross-checked with the ones stored after them.
+pub unsafe fn io_uring_layout_ok(ring: *const io_uring) -> bool {
+    let sq = &(*ring).sq;
+    let cq = &(*ring).cq;
+
+    if sq.kring_mask.is_null()
+        || sq.kring_entries.is_null()
+        || cq.kring_mask.is_null()
+        || cq.kring_entries.is_null()
+        || sq.ring_ptr.is_null()
+        || cq.ring_ptr.is_null()
+    {
+        return false;
+    }
+
+    let sq_entries = *sq.kring_entries;
+    let cq_entries = *cq.kring_entries;
+    if !sq_entries.is_power_of_two()
+        || !cq_entries.is_power_of_two()
+        || *sq.kring_mask != sq_entries - 1
+        || *cq.kring_mask != cq_entries - 1
+    {
+        return false;
+    }
+
+    #[cfg(liburing_2_3)]
+    if sq.ring_mask != *sq.kring_mask
+        || sq.ring_entries != sq_entries
+        || cq.ring_mask != *cq.kring_mask
+        || cq.ring_entries != cq_entries
+    {
+        return false;
+    }
+
+    fd_is_open((*ring).ring_fd)
 }
-d!(io_uring_sqe_union_5_ty);
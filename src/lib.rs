@@ -2,11 +2,21 @@
 #![allow(dead_code)]
 #![allow(clippy::missing_safety_doc)] // FIXME
 
+#[cfg(feature = "libublksrv")]
 pub mod aio;
 pub mod cmd;
-mod iouring;
+pub mod ctrl;
+mod error;
+#[cfg(feature = "libublksrv")]
+pub mod iouring;
+pub mod queue;
+#[cfg(feature = "libublksrv")]
 pub mod srv;
+mod uring;
 
+pub use error::{Error, ErrorKind, Op, Result};
+
+#[cfg(feature = "libublksrv")]
 macro_rules! d {
     ($i:ident) => {
         impl Default for $i {
@@ -21,6 +31,7 @@ macro_rules! d {
     };
 }
 
+#[cfg(feature = "libublksrv")]
 pub(crate) use d;
 
 #[repr(C)]
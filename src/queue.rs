@@ -0,0 +1,390 @@
+// SPDX-License-Identifier: MIT
+//! Queues driven without libublksrv: the descriptors of the requests are
+//! mapped from the char device and the IO commands are sent on a ring of
+//! our own.
+
+use crate::cmd::{self, ublksrv_ctrl_dev_info, ublksrv_io_cmd, ublksrv_io_desc, IoOp};
+use crate::error::{Error, Op, Result};
+use crate::uring::{Mmap, Ring};
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+
+mod per_io;
+pub use per_io::PerIoQueue;
+
+fn page_round(len: usize) -> usize {
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    (len + page - 1) & !(page - 1)
+}
+
+/// Error of `op` on queue `q_id` of the device described by `info`
+fn queue_err(op: Op, info: &ublksrv_ctrl_dev_info, q_id: u16) -> impl FnOnce(io::Error) -> Error {
+    let dev_id = info.dev_id;
+    move |e| Error::new(op, e).with_dev(dev_id).with_queue(q_id)
+}
+
+/// Checks that queue `q_id` exists in the device described by `info`, and
+/// that the device can be driven here
+fn check_queue(info: &ublksrv_ctrl_dev_info, q_id: u16) -> Result<()> {
+    if q_id >= info.nr_hw_queues || info.queue_depth == 0 {
+        let err = io::Error::from(io::ErrorKind::InvalidInput);
+        return Err(queue_err(Op::QueueInit, info, q_id)(err));
+    }
+
+    // the driver would take the address of our buffers for a
+    // `ublk_auto_buf_reg`
+    if info.flags & cmd::UBLK_F_AUTO_BUF_REG as u64 != 0 {
+        let err = io::Error::from_raw_os_error(libc::EOPNOTSUPP);
+        return Err(queue_err(Op::QueueInit, info, q_id)(err));
+    }
+    Ok(())
+}
+
+/// Result of a request, committed with the fetch of the next request of
+/// its tag. A handler returning the `i32` result alone is enough for all
+/// the requests but `IoOp::ZoneAppend`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Completion {
+    /// Number of bytes handled or a negative errno
+    pub result: i32,
+    /// Sector a ZONE_APPEND request wrote its data at, the driver reads it
+    /// instead of the buffer address
+    pub zone_append_lba: Option<u64>,
+}
+
+impl Completion {
+    /// Completion of a ZONE_APPEND request that wrote `result` bytes at
+    /// sector `lba`
+    pub fn zone_append(result: i32, lba: u64) -> Self {
+        Completion {
+            result,
+            zone_append_lba: Some(lba),
+        }
+    }
+}
+
+impl From<i32> for Completion {
+    fn from(result: i32) -> Self {
+        Completion {
+            result,
+            zone_append_lba: None,
+        }
+    }
+}
+
+/// Maps the descriptors of the requests of queue `q_id`, indexed by tag
+fn map_iods(cdev: RawFd, q_id: u16, q_depth: u16) -> io::Result<Mmap> {
+    let iod_size = std::mem::size_of::<ublksrv_io_desc>();
+    let max_size = page_round(cmd::UBLK_MAX_QUEUE_DEPTH as usize * iod_size);
+    let offset = cmd::UBLKSRV_CMD_BUF_OFFSET as usize + q_id as usize * max_size;
+
+    Mmap::new_ro(cdev, offset as i64, page_round(q_depth as usize * iod_size))
+}
+
+/// Some tags of a queue, fetched and committed on one ring.
+///
+/// The tags are `first, first + step, ...`, below the queue depth.
+struct Tags {
+    cdev: RawFd,
+    dev_flags: u64,
+    q_id: u16,
+    tags: Vec<u16>,
+    step: usize,
+    ring: Ring,
+    /// one buffer per tag, `None` with `UBLK_F_USER_COPY`
+    bufs: Option<Mmap>,
+    buf_len: usize,
+    stride: usize,
+    /// tags with a command in flight, or about to be submitted
+    live: usize,
+    /// tag whose request is being handled, not committed yet
+    handling: Option<u16>,
+}
+
+impl Tags {
+    fn new(
+        cdev: RawFd,
+        info: &ublksrv_ctrl_dev_info,
+        q_id: u16,
+        first: u16,
+        step: usize,
+    ) -> io::Result<Self> {
+        let tags: Vec<u16> = (first..info.queue_depth).step_by(step).collect();
+        let ring = Ring::new(tags.len() as u32, 0)?;
+
+        let buf_len = if info.flags & cmd::UBLK_F_USER_COPY as u64 != 0 {
+            0
+        } else {
+            info.max_io_buf_bytes as usize
+        };
+        let stride = page_round(buf_len);
+        let bufs = match stride {
+            0 => None,
+            _ => Some(Mmap::anon(tags.len() * stride)?),
+        };
+
+        Ok(Tags {
+            cdev,
+            dev_flags: info.flags,
+            q_id,
+            live: 0,
+            handling: None,
+            tags,
+            step,
+            ring,
+            bufs,
+            buf_len,
+            stride,
+        })
+    }
+
+    /// Buffer of the i-th tag
+    fn buf(&self, i: usize) -> *mut u8 {
+        match &self.bufs {
+            Some(bufs) => unsafe { bufs.as_ptr().add(i * self.stride) },
+            None => std::ptr::null_mut(),
+        }
+    }
+
+    /// Queues `cmd_op` for `tag`, with the buffer of the tag or, when
+    /// committing a ZONE_APPEND, the sector it wrote at
+    fn queue_cmd(&mut self, cmd_op: u32, tag: u16, done: Completion) -> io::Result<()> {
+        let mut io_cmd = ublksrv_io_cmd {
+            q_id: self.q_id,
+            tag,
+            result: done.result,
+            addr: self.buf(tag as usize / self.step) as u64,
+        };
+        if let Some(lba) = done.zone_append_lba {
+            io_cmd.set_zone_append_lba(lba);
+        }
+        let payload = unsafe {
+            std::slice::from_raw_parts(
+                &io_cmd as *const ublksrv_io_cmd as *const u8,
+                std::mem::size_of::<ublksrv_io_cmd>(),
+            )
+        };
+
+        self.ring.prep_uring_cmd(
+            self.cdev,
+            cmd::ublk_io_cmd_op(self.dev_flags, cmd_op),
+            payload,
+            tag as u64,
+        )
+    }
+
+    /// Queues the fetch commands of all the tags
+    fn fetch(&mut self) -> io::Result<()> {
+        for i in 0..self.tags.len() {
+            self.queue_cmd(cmd::UBLK_IO_FETCH_REQ, self.tags[i], 0.into())?;
+            self.live += 1;
+        }
+        Ok(())
+    }
+
+    /// Submits the queued commands, waits for at least one completion and
+    /// handles all those available. Returns false once every tag has been
+    /// aborted.
+    fn process<F, R>(&mut self, iods: &Mmap, handle: &mut F) -> io::Result<bool>
+    where
+        F: FnMut(u16, &ublksrv_io_desc, &mut [u8]) -> R,
+        R: Into<Completion>,
+    {
+        if self.live == 0 {
+            return Ok(false);
+        }
+        let pending = self.ring.pending() as usize;
+        if let Err(e) = self.ring.submit_and_wait(1) {
+            // the queued commands were dropped, their tags are lost
+            self.live -= pending;
+            return Err(e);
+        }
+
+        while let Some(cqe) = self.ring.pop_cqe() {
+            let tag = cqe.user_data as u16;
+
+            match cqe.res {
+                cmd::UBLK_IO_RES_ABORT => self.live -= 1,
+                res if res == cmd::UBLK_IO_RES_NEED_GET_DATA as i32 => {
+                    self.queue_cmd(cmd::UBLK_IO_NEED_GET_DATA, tag, 0.into())?;
+                }
+                res if res == cmd::UBLK_IO_RES_OK as i32 => {
+                    let iod = unsafe {
+                        std::ptr::read_volatile(
+                            (iods.as_ptr() as *const ublksrv_io_desc).add(tag as usize),
+                        )
+                    };
+                    let data = match self.buf_len {
+                        0 => &mut [][..],
+                        len => unsafe {
+                            std::slice::from_raw_parts_mut(self.buf(tag as usize / self.step), len)
+                        },
+                    };
+                    self.handling = Some(tag);
+                    let mut done = handle(tag, &iod, data).into();
+                    if iod.op() != IoOp::ZoneAppend {
+                        done.zone_append_lba = None;
+                    } else if done.result >= 0 && done.zone_append_lba.is_none() {
+                        // the driver would take the buffer address for it
+                        done.result = -libc::EIO;
+                    }
+                    self.queue_cmd(cmd::UBLK_IO_COMMIT_AND_FETCH_REQ, tag, done)?;
+                    self.handling = None;
+                }
+                res => {
+                    // the tag isn't fetched again
+                    self.live -= 1;
+                    return Err(io::Error::from_raw_os_error(-res));
+                }
+            }
+        }
+
+        Ok(self.live > 0)
+    }
+
+    /// Once the device is stopped, waits until the commands of all the tags
+    /// are aborted, failing the requests delivered meanwhile with `EIO`.
+    /// Returns false if the ring failed, the commands may still be in
+    /// flight then.
+    fn drain(&mut self) -> bool {
+        // the handler of its request may have panicked
+        if let Some(tag) = self.handling.take() {
+            let failed = (-libc::EIO).into();
+            if self
+                .queue_cmd(cmd::UBLK_IO_COMMIT_AND_FETCH_REQ, tag, failed)
+                .is_err()
+            {
+                return false;
+            }
+        }
+
+        while self.live > 0 {
+            if self.ring.submit_and_wait(1).is_err() {
+                return false;
+            }
+
+            while let Some(cqe) = self.ring.pop_cqe() {
+                let tag = cqe.user_data as u16;
+                let queued = match cqe.res {
+                    res if res == cmd::UBLK_IO_RES_NEED_GET_DATA as i32 => {
+                        self.queue_cmd(cmd::UBLK_IO_NEED_GET_DATA, tag, 0.into())
+                    }
+                    res if res == cmd::UBLK_IO_RES_OK as i32 => {
+                        self.queue_cmd(cmd::UBLK_IO_COMMIT_AND_FETCH_REQ, tag, (-libc::EIO).into())
+                    }
+                    _ => {
+                        self.live -= 1;
+                        Ok(())
+                    }
+                };
+                if queued.is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Queue of a device run by one thread, the native counterpart of
+/// `ublksrv_queue_init`, `ublksrv_process_io` and `ublksrv_complete_io`.
+///
+/// Each tag gets a buffer of `max_io_buf_bytes`, whose address goes with
+/// its commands, unless the device has `UBLK_F_USER_COPY`. Devices with
+/// `UBLK_F_AUTO_BUF_REG`, where the commands carry a fixed buffer index
+/// instead, are not supported.
+pub struct Queue<'a> {
+    cdev: BorrowedFd<'a>,
+    dev_id: u32,
+    q_id: u16,
+    iods: Mmap,
+    tags: Tags,
+    fetched: bool,
+}
+
+impl<'a> Queue<'a> {
+    /// Queue `q_id` of the device described by `info`, whose char device is
+    /// `cdev`.
+    ///
+    /// The queue must not be set up with `ublksrv_queue_init` too.
+    pub fn new(cdev: BorrowedFd<'a>, info: &ublksrv_ctrl_dev_info, q_id: u16) -> Result<Self> {
+        check_queue(info, q_id)?;
+
+        let fd = cdev.as_raw_fd();
+        Ok(Queue {
+            cdev,
+            dev_id: info.dev_id,
+            q_id,
+            iods: map_iods(fd, q_id, info.queue_depth).map_err(queue_err(
+                Op::QueueInit,
+                info,
+                q_id,
+            ))?,
+            tags: Tags::new(fd, info, q_id, 0, 1).map_err(queue_err(Op::QueueInit, info, q_id))?,
+            fetched: false,
+        })
+    }
+
+    pub fn q_id(&self) -> u16 {
+        self.q_id
+    }
+
+    pub fn q_depth(&self) -> u16 {
+        self.tags.tags.len() as u16
+    }
+
+    pub fn cdev(&self) -> BorrowedFd<'a> {
+        self.cdev
+    }
+
+    /// Descriptor of the current request of `tag`
+    pub fn iod(&self, tag: u16) -> ublksrv_io_desc {
+        assert!(tag < self.q_depth());
+        unsafe {
+            std::ptr::read_volatile(
+                (self.iods.as_ptr() as *const ublksrv_io_desc).add(tag as usize),
+            )
+        }
+    }
+
+    /// Waits for requests and hands each of them to `handle(tag, iod, buf)`,
+    /// whose result is committed with the fetch of the next request.
+    ///
+    /// `buf` holds the data of a WRITE and receives the data of a READ, it
+    /// is empty with `UBLK_F_USER_COPY`, where the data is copied with
+    /// `cmd::UserCopy`. The result is the number of bytes handled or a
+    /// negative errno. A ZONE_APPEND request has to complete with
+    /// `Completion::zone_append()`, it fails with `EIO` otherwise.
+    ///
+    /// The first call fetches all the tags, the device is then started from
+    /// another thread. Returns false once the device is stopped and all its
+    /// commands were aborted.
+    pub fn process_io<F, R>(&mut self, mut handle: F) -> Result<bool>
+    where
+        F: FnMut(u16, &ublksrv_io_desc, &mut [u8]) -> R,
+        R: Into<Completion>,
+    {
+        let (dev_id, q_id) = (self.dev_id, self.q_id);
+        let err = |e| {
+            Error::new(Op::ProcessIo, e)
+                .with_dev(dev_id)
+                .with_queue(q_id)
+        };
+
+        if !self.fetched {
+            self.tags.fetch().map_err(err)?;
+            self.fetched = true;
+        }
+        self.tags.process(&self.iods, &mut handle).map_err(err)
+    }
+
+    /// Calls `process_io()` until the device is stopped
+    pub fn run<F, R>(&mut self, mut handle: F) -> Result<()>
+    where
+        F: FnMut(u16, &ublksrv_io_desc, &mut [u8]) -> R,
+        R: Into<Completion>,
+    {
+        while self.process_io(&mut handle)? {}
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: MIT
+//! Queue runtime spreading the tags of a queue over a pool of threads
+//! (`UBLK_F_PER_IO_DAEMON`), driven without libublksrv.
+
+use super::{check_queue, map_iods, queue_err, Completion, Tags};
+use crate::cmd::{self, ublksrv_ctrl_dev_info, ublksrv_io_desc};
+use crate::ctrl;
+use crate::error::{Op, Result};
+use crate::uring::Mmap;
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::panic::{self, AssertUnwindSafe};
+
+/// Queue of a device run by a pool of threads.
+///
+/// Tag `t` is owned by thread `t % nr_threads`, which fetches it, hands its
+/// requests to the handler and commits them on its own ring. A tag is so
+/// always fetched and committed by the same thread, and only committed once
+/// its fetch completed, as the driver requires.
+///
+/// Spreading the tags of a queue needs `UBLK_F_PER_IO_DAEMON`, without it
+/// every tag has to be handled by the thread that fetched the first one,
+/// and the pool can only have one thread.
+///
+/// Devices with `UBLK_F_AUTO_BUF_REG` are not supported, as with `Queue`.
+#[derive(Debug)]
+pub struct PerIoQueue<'a> {
+    cdev: BorrowedFd<'a>,
+    info: ublksrv_ctrl_dev_info,
+    q_id: u16,
+    nr_threads: usize,
+}
+
+impl<'a> PerIoQueue<'a> {
+    /// Queue `q_id` of the device described by `info`, whose char device is
+    /// `cdev` (see `ublksrv_get_cdev_fd()`), run by `nr_threads` threads.
+    ///
+    /// The queue must not be set up with `ublksrv_queue_init` too.
+    pub fn new(
+        cdev: BorrowedFd<'a>,
+        info: &ublksrv_ctrl_dev_info,
+        q_id: u16,
+        nr_threads: usize,
+    ) -> Result<Self> {
+        check_queue(info, q_id)?;
+
+        let per_io = info.flags & cmd::UBLK_F_PER_IO_DAEMON as u64 != 0;
+        if nr_threads == 0 || nr_threads > info.queue_depth as usize || (nr_threads > 1 && !per_io)
+        {
+            let err = io::Error::from(io::ErrorKind::InvalidInput);
+            return Err(queue_err(Op::QueueInit, info, q_id)(err));
+        }
+
+        Ok(PerIoQueue {
+            cdev,
+            info: *info,
+            q_id,
+            nr_threads,
+        })
+    }
+
+    /// Runs the queue until the device is stopped.
+    ///
+    /// `handle(tag, iod, buf)` is called for each request, on the thread
+    /// owning `tag`, as with `Queue::process_io()`.
+    ///
+    /// The fetch commands are sent as soon as the threads start, the device
+    /// is started from another thread. Returns the first error of the
+    /// threads, once they are all done.
+    ///
+    /// If a thread fails, or its handler panics, the others would wait for
+    /// requests forever and its own commands would still point to its
+    /// buffers. It so stops the device, which aborts the commands of all
+    /// the threads. Stopping the device waits for the requests already
+    /// delivered, so it is done from another thread while this one fails
+    /// them with `EIO`, until its commands are aborted too. The panic is
+    /// resumed once all the threads are done, and the device then has to
+    /// be deleted or recovered. Should `/dev/ublk-control` fail to open,
+    /// the thread leaks its buffers and the other threads keep running.
+    ///
+    /// # Examples
+    ///
+    /// A handler failing a request by panicking stops the whole queue:
+    ///
+    /// ```no_run
+    /// # use ublk_sys::cmd::IoOp;
+    /// # use ublk_sys::queue::PerIoQueue;
+    /// # use std::panic::{catch_unwind, AssertUnwindSafe};
+    /// # fn serve(queue: &PerIoQueue) {
+    /// let ret = catch_unwind(AssertUnwindSafe(|| {
+    ///     queue.run(|_tag, iod, buf| match iod.op() {
+    ///         IoOp::Read | IoOp::Write => buf.len() as i32,
+    ///         op => panic!("unexpected {op:?}"),
+    ///     })
+    /// }));
+    /// // all the threads are done and the device is stopped
+    /// assert!(ret.is_err());
+    /// # }
+    /// ```
+    pub fn run<F, R>(&self, handle: F) -> Result<()>
+    where
+        F: Fn(u16, &ublksrv_io_desc, &mut [u8]) -> R + Sync,
+        R: Into<Completion>,
+    {
+        let iods = map_iods(self.cdev.as_raw_fd(), self.q_id, self.info.queue_depth)
+            .map_err(queue_err(Op::QueueInit, &self.info, self.q_id))?;
+
+        std::thread::scope(|s| {
+            let workers: Vec<_> = (0..self.nr_threads)
+                .map(|i| {
+                    let (iods, handle) = (&iods, &handle);
+                    s.spawn(move || self.run_tags(iods, i as u16, handle))
+                })
+                .collect();
+
+            let results: Vec<_> = workers
+                .into_iter()
+                .map(|w| w.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect();
+            results.into_iter().collect()
+        })
+    }
+
+    /// Stops the device, so that the commands of every thread are aborted
+    fn stop_dev(&self) -> Result<()> {
+        ctrl::Control::open()?.stop_dev(self.info.dev_id, self.info.flags)
+    }
+
+    /// Stops the device and fails the requests `tags` is delivered until
+    /// its commands are aborted. Returns false if they may still be in
+    /// flight.
+    fn stop_and_drain(&self, tags: &mut Tags) -> bool {
+        let Ok(mut ctrl) = ctrl::Control::open() else {
+            return false;
+        };
+
+        // STOP_DEV only completes once the requests this thread holds are
+        // committed, by drain(). It isn't joined: the thread is done with
+        // the device either way.
+        let (dev_id, dev_flags) = (self.info.dev_id, self.info.flags);
+        std::thread::spawn(move || ctrl.stop_dev(dev_id, dev_flags));
+        tags.drain()
+    }
+
+    /// Runs the tags of thread `first`
+    fn run_tags<F, R>(&self, iods: &Mmap, first: u16, handle: &F) -> Result<()>
+    where
+        F: Fn(u16, &ublksrv_io_desc, &mut [u8]) -> R,
+        R: Into<Completion>,
+    {
+        let mut tags = match Tags::new(
+            self.cdev.as_raw_fd(),
+            &self.info,
+            self.q_id,
+            first,
+            self.nr_threads,
+        ) {
+            Ok(tags) => tags,
+            Err(e) => {
+                // nothing of this thread is in flight yet
+                let _ = self.stop_dev();
+                return Err(queue_err(Op::QueueInit, &self.info, self.q_id)(e));
+            }
+        };
+
+        let processed = panic::catch_unwind(AssertUnwindSafe(|| {
+            tags.fetch()?;
+            while tags.process(iods, &mut |tag, iod, buf| handle(tag, iod, buf))? {}
+            Ok(())
+        }));
+        if let Ok(Ok(())) = processed {
+            return Ok(());
+        }
+
+        if !self.stop_and_drain(&mut tags) {
+            // the driver may still write to the buffers
+            std::mem::forget(tags);
+        }
+        match processed {
+            Ok(ret) => ret.map_err(queue_err(Op::ProcessIo, &self.info, self.q_id)),
+            Err(e) => panic::resume_unwind(e),
+        }
+    }
+}
@@ -1,272 +1,49 @@
 // SPDX-License-Identifier: MIT
-#![allow(dead_code)]
-#![allow(non_camel_case_types)]
 #![allow(clippy::missing_safety_doc)] // FIXME
 
-use crate::iouring;
-use crate::{__IncompleteArrayField, cmd, d};
-use libc::{__u64, c_char, c_int, c_long, c_uint, c_ulong, c_ulonglong, c_ushort, c_void};
-
-pub const MAX_NR_HW_QUEUES: u32 = 32;
-pub const MAX_QD: u32 = 1024;
-pub const MAX_BUF_SIZE: u32 = 1024 << 10;
-
-pub const DEF_NR_HW_QUEUES: u32 = 1;
-pub const DEF_QD: u32 = 256;
-pub const DEF_BUF_SIZE: u32 = 512 << 10;
-
-pub const UBLKSRV_SHM_DIR: &[u8; 8] = b"ublksrv\0";
-pub const UBLKSRV_SHM_SIZE: u32 = 1024;
-
-/// stored in ublksrv_ctrl_dev_info->ublksrv_flags
-
-/// HAS_IO_DAEMON means io handler has its own daemon context which isn't
-/// same with control command context, so shared memory communication is
-/// required between control task and io daemon
-pub const UBLKSRV_F_HAS_IO_DAEMON: u32 = 1;
-
-/// target may not use io_uring for handling io, so eventfd is required
-/// for wakeup io command io_uring context
-pub const UBLKSRV_F_NEED_EVENTFD: u32 = 2;
-
-// A opaque type
-// TODO: Replace with a extern type once RFC 1861 becomes stable
-// #![feature(extern_types)]
-// extern "C" {
-//     type ublksrv_aio_ctx;
-// }
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct ublksrv_aio_ctx {
-    _private: [u8; 0],
+use crate::uring::Sqe;
+use crate::{cmd, ctrl, iouring};
+use libc::{__u64, c_char, c_int, c_uint};
+use std::ffi::CStr;
+use std::os::fd::BorrowedFd;
+use std::path::Path;
+
+#[cfg(all(not(feature = "bindgen"), not(feature = "libublksrv-1")))]
+mod raw;
+#[cfg(all(not(feature = "bindgen"), feature = "libublksrv-1"))]
+#[path = "srv/raw_v1.rs"]
+mod raw;
+/// Generated from `ublksrv.h` at build time
+#[cfg(feature = "bindgen")]
+mod raw {
+    #![allow(non_camel_case_types, non_upper_case_globals, dead_code)]
+    use crate::cmd::*;
+    use crate::iouring::*;
+    use libc::{cpu_set_t, pthread_t};
+    include!(concat!(env!("OUT_DIR"), "/ublksrv.rs"));
 }
+pub use raw::*;
 
-/// Generic data for creating one ublk device
-///
-/// Target specific data is handled by ->init_tgt
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct ublksrv_dev_data {
-    pub dev_id: c_int,
-    pub max_io_buf_bytes: c_uint,
-    pub nr_hw_queues: c_ushort,
-    pub queue_depth: c_ushort,
-    pub tgt_type: *const c_char,
-    pub tgt_ops: *const ublksrv_tgt_type,
-    pub tgt_argc: c_int,
-    pub tgt_argv: *mut *mut c_char,
-    pub run_dir: *const c_char,
-    pub flags: c_ulong,
-    pub ublksrv_flags: c_ulong,
-    pub reserved: [c_ulong; 7],
-}
-d!(ublksrv_dev_data);
-
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct ublksrv_ctrl_dev {
-    pub ring: iouring::io_uring,
-    pub ctrl_fd: c_int,
-    pub bs_shift: c_uint,
-    pub dev_info: cmd::ublksrv_ctrl_dev_info,
-    pub tgt_type: *const c_char,
-    pub tgt_ops: *const ublksrv_tgt_type,
-    /// default is UBLKSRV_RUN_DIR but can be specified via command line,
-    /// pid file will be saved there
-    pub run_dir: *const c_char,
-    pub tgt_argc: c_int,
-    pub tgt_argv: *mut *mut c_char,
-    pub queues_cpuset: *mut libc::cpu_set_t,
-}
-d!(ublksrv_ctrl_dev);
-
-pub const UBLKSRV_NEED_FETCH_RQ: u32 = 1 << 0;
-pub const UBLKSRV_NEED_COMMIT_RQ_COMP: u32 = 1 << 1;
-pub const UBLKSRV_IO_FREE: u32 = 1 << 2;
-pub const UBLKSRV_NEED_GET_DATA: u32 = 1 << 3;
-
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct ublk_io {
-    pub buf_addr: *mut c_char,
-    pub flags: c_uint,
-    pub union: ublk_io_anon_union_ty,
-    pub tgt_io_cqe: *mut iouring::io_uring_cqe,
-    pub io_data: c_ulong,
-}
-d!(ublk_io);
-
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub union ublk_io_anon_union_ty {
-    /// result is updated after all target ios are done
-    pub result: c_uint,
-    /// current completed target io cqe
-    pub queued_tgt_io: c_int,
-}
-d!(ublk_io_anon_union_ty);
-
-pub const UBLKSRV_QUEUE_STOPPING: u32 = 1;
-pub const UBLKSRV_QUEUE_IDLE: u32 = 2;
-pub const UBLKSRV_NR_CTX_BATCH: u32 = 4;
-
-#[repr(C)]
-pub struct ublksrv_queue {
-    pub q_id: c_int,
-    pub q_depth: c_int,
-    pub private_data: *mut c_void,
-    /// Read only by ublksrv daemon, setup via mmap on /dev/ublkcN.
-    ///
-    /// ublksrv_io_desc(iod) is stored in this buffer, so iod
-    /// can be retrieved by request's tag directly.
-    ///
-    /// ublksrv writes the iod into this array, and notify ublksrv daemon
-    /// by issued io_uring command beforehand.
-    pub io_cmd_buf: *mut c_char,
-    pub io_buf: *mut c_char,
-    pub cmd_inflight: c_uint,
-    pub tgt_io_inflight: c_uint,
-    pub state: c_uint,
-    /// eventfd
-    pub efd: c_int,
-    /// cache tgt ops
-    pub tgt_ops: *const ublksrv_tgt_type,
-    /// ring for submit io command to ublk driver, can only be issued from ublksrv daemon.
-    /// ring depth == dev_info->queue_depth.
-    pub ring: iouring::io_uring,
-    pub dev: *mut ublksrv_dev,
-    pub tid: c_uint,
-    pub nr_ctxs: c_int,
-    pub ctxs: [*mut ublksrv_aio_ctx; UBLKSRV_NR_CTX_BATCH as usize],
-    pub ios: __IncompleteArrayField<ublk_io>,
-}
-d!(ublksrv_queue);
-
-pub const UBLKSRV_TGT_MAX_FDS: u32 = 32;
-
-// enum
-/// evaluate communication cost, ublksrv_null vs /dev/nullb0
-pub const UBLKSRV_TGT_TYPE_NULL: c_uint = 0;
-/// ublksrv_loop vs. /dev/loop
-pub const UBLKSRV_TGT_TYPE_LOOP: c_uint = 1;
-pub const UBLKSRV_TGT_TYPE_MAX: c_uint = 256;
-
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct ublksrv_tgt_info {
-    pub dev_size: c_ulonglong,
-    /// at most in-flight ios
-    pub tgt_ring_depth: c_uint,
-    pub nr_fds: c_uint,
-    pub fds: [c_int; UBLKSRV_TGT_MAX_FDS as usize],
-    pub tgt_data: *mut c_void,
-    pub ops: *const ublksrv_tgt_type,
-}
-d!(ublksrv_tgt_info);
-
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct ublksrv_tgt_type {
-    /// One IO request comes from /dev/ublkbN, so notify target code
-    /// for handling the IO. Inside target code, the IO can be handled
-    /// with our io_uring too, if this is true, ->tgt_io_done callback
-    /// has to be implemented. Otherwise, target can implement
-    /// ->handle_event() for processing io completion there.
-    pub handle_io_async:
-        Option<unsafe extern "C" fn(arg1: *mut ublksrv_queue, tag: c_int) -> c_int>,
-
-    /// target io is handled by our io_uring, and once the target io
-    /// is completed, this callback is called
-    pub tgt_io_done:
-        Option<unsafe extern "C" fn(arg1: *mut ublksrv_queue, arg2: *mut iouring::io_uring_cqe)>,
-
-    /// Someone has written to our eventfd, so let target handle the
-    /// event, most of times, it is for handling io completion by
-    /// calling ublksrv_complete_io() which has to be run in ubq_daemon context.
-    ///
-    /// Follows the typical scenario:
-    ///
-    /// 1) one target io is completed in target pthread context, so
-    /// target code calls ublksrv_queue_send_event for notifying ubq daemon
-    ///
-    /// 2) ubq daemon gets notified, so wakeup from io_uring_enter(),
-    /// then found eventfd is completed, so call ->handle_event()
-    ///
-    /// 3) inside ->handle_event(), if any io represented by one io
-    /// command is completed, ublksrv_complete_io() is called for this io.
-    ///
-    /// 4) after returning from ->handle_event(), ubq_daemon will
-    /// queue & submit the eventfd io immediately for getting
-    /// notification from future event.
-    pub handle_event: Option<unsafe extern "C" fn(arg1: *mut ublksrv_queue)>,
-
-    /// One typical use case is to flush meta data, which is usually done
-    /// in background. So there isn't any tag from libublksrv for this kind
-    /// of IOs, and the target code has to request for allocating extra ios
-    /// by passing tgt_type->extra_ios and let this callback consume & handle
-    /// these extra IOs.
-    ///
-    /// @nr_queued_io: count of queued IOs in ublksrv_reap_events_uring of this time
-    pub handle_io_background:
-        Option<unsafe extern "C" fn(arg1: *mut ublksrv_queue, nr_queued_io: c_int)>,
-
-    /// show target specific command line for adding new device
-    ///
-    /// Be careful: this callback is the only one which is not run from
-    /// ublk device daemon task context.
-    pub usage_for_add: Option<unsafe extern "C" fn()>,
-
-    /// initialize this new target, argc/argv includes target specific
-    /// command line parameters
-    pub init_tgt: Option<
-        unsafe extern "C" fn(
-            arg1: *mut ublksrv_dev,
-            type_: c_int,
-            argc: c_int,
-            argv: *mut *mut c_char,
-        ) -> c_int,
-    >,
-
-    /// deinitialize this target
-    pub deinit_tgt: Option<unsafe extern "C" fn(arg1: *mut ublksrv_dev)>,
-
-    pub alloc_io_buf:
-        Option<unsafe extern "C" fn(q: *mut ublksrv_queue, tag: c_int, size: c_int) -> *mut c_void>,
-    pub free_io_buf:
-        Option<unsafe extern "C" fn(q: *mut ublksrv_queue, buf: *mut c_void, tag: c_int)>,
-
-    pub type_: c_int,
-
-    /// flags required for ublk driver
-    pub ublk_flags: c_uint,
-    /// flags required for ublksrv
-    pub ublksrv_flags: c_uint,
-    /// extra io slots allocated for handling
-    pub extra_ios: c_int,
-    /// target specific IOs, such as meta io
-    pub name: *const c_char,
-}
-d!(ublksrv_tgt_type);
-
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct ublksrv_dev {
-    pub tgt: ublksrv_tgt_info,
-    pub __queues: [*mut ublksrv_queue; MAX_NR_HW_QUEUES as usize],
-    pub io_buf_start: *mut c_char,
-    pub thread: *mut libc::pthread_t,
-    pub cdev_fd: c_int,
-    pub pid_file_fd: c_int,
-    pub ctrl_dev: *const ublksrv_ctrl_dev,
-    pub target_data: *mut c_void,
-}
-d!(ublksrv_dev);
+pub use crate::queue::PerIoQueue;
+
+mod ctrl_dev;
+mod dev_data;
+pub use ctrl_dev::CtrlDev;
+pub use dev_data::{DevData, DevDataBuilder};
 
+#[cfg(not(feature = "libublksrv-1"))]
 pub unsafe fn ublksrv_get_iod(q: *const ublksrv_queue, tag: c_int) -> *mut cmd::ublksrv_io_desc {
     let idx = tag as usize * std::mem::size_of::<cmd::ublksrv_io_desc>();
     (*q).io_cmd_buf.add(idx) as *mut cmd::ublksrv_io_desc
 }
 
+/// libublksrv 1.x hands the iod to the target in `ublk_io_data`, this is
+/// for code that only has the tag.
+#[cfg(feature = "libublksrv-1")]
+pub unsafe fn ublksrv_get_iod(q: *const ublksrv_queue, tag: c_int) -> *const cmd::ublksrv_io_desc {
+    (*ublksrv_queue_get_io_data(q, tag)).iod
+}
+
 pub unsafe fn build_user_data(
     tag: c_uint,
     op: c_uint,
@@ -289,134 +66,258 @@ pub unsafe fn user_data_to_tgt_data(user_data: __u64) -> c_int {
     ((user_data >> 24) & 0xffff) as c_int
 }
 
-extern "C" {
-    pub fn ublksrv_ctrl_deinit(dev: *mut ublksrv_ctrl_dev);
-    pub fn ublksrv_ctrl_init(data: *mut ublksrv_dev_data) -> *mut ublksrv_ctrl_dev;
-    pub fn ublksrv_ctrl_get_affinity(ctrl_dev: *mut ublksrv_ctrl_dev) -> c_int;
-    pub fn ublksrv_ctrl_add_dev(dev: *mut ublksrv_ctrl_dev) -> c_int;
-    pub fn ublksrv_ctrl_del_dev(dev: *mut ublksrv_ctrl_dev) -> c_int;
-    pub fn ublksrv_ctrl_get_info(dev: *mut ublksrv_ctrl_dev) -> c_int;
-    pub fn ublksrv_ctrl_stop_dev(dev: *mut ublksrv_ctrl_dev) -> c_int;
-    pub fn ublksrv_ctrl_dump(dev: *mut ublksrv_ctrl_dev, buf: *const c_char);
-    pub fn ublksrv_ctrl_start_dev(ctrl_dev: *mut ublksrv_ctrl_dev, daemon_pid: c_int) -> c_int;
-    pub fn ublksrv_ctrl_set_params(
-        dev: *mut ublksrv_ctrl_dev,
-        params: *mut cmd::ublk_params,
-    ) -> c_int;
-    pub fn ublksrv_ctrl_get_params(
-        dev: *mut ublksrv_ctrl_dev,
-        params: *mut cmd::ublk_params,
-    ) -> c_int;
-    pub fn ublksrv_dev_init(ctrl_dev: *const ublksrv_ctrl_dev) -> *mut ublksrv_dev;
-    pub fn ublksrv_dev_deinit(dev: *mut ublksrv_dev);
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_queue_get_data(q: *const ublksrv_queue) -> *mut libc::c_void {
+    (*q).private_data
 }
 
-/// target json has to include the following key/value
-pub const UBLKSRV_TGT_NAME_MAX_LEN: u32 = 32;
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-pub struct ublksrv_tgt_base_json {
-    pub name: [c_char; UBLKSRV_TGT_NAME_MAX_LEN as usize],
-    pub type_: c_int,
-    pub dev_size: c_ulonglong,
+/// Ring of `q`, the target queues its own IO there too
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_queue_get_ring(q: *const ublksrv_queue) -> *mut iouring::io_uring {
+    &(*q).ring as *const iouring::io_uring as *mut iouring::io_uring
 }
 
-extern "C" {
-    pub fn ublksrv_json_write_dev_info(
-        dev: *const ublksrv_ctrl_dev,
-        buf: *mut c_char,
-        len: c_int,
-    ) -> c_int;
-    pub fn ublksrv_json_read_dev_info(
-        json_buf: *const c_char,
-        info: *mut cmd::ublksrv_ctrl_dev_info,
-    ) -> c_int;
-    pub fn ublksrv_json_write_queue_info(
-        dev: *const ublksrv_ctrl_dev,
-        jbuf: *mut c_char,
-        len: c_int,
-        qid: c_int,
-        ubq_daemon_tid: c_int,
-    ) -> c_int;
-    pub fn ublksrv_json_read_queue_info(
-        jbuf: *const c_char,
-        qid: c_int,
-        tid: *mut c_uint,
-        affinity_buf: *mut c_char,
-        len: c_int,
-    ) -> c_int;
-    pub fn ublksrv_json_read_target_info(
-        jbuf: *const c_char,
-        tgt_buf: *mut c_char,
-        len: c_int,
-    ) -> c_int;
-    pub fn ublksrv_json_write_target_str_info(
-        jbuf: *mut c_char,
-        len: c_int,
-        name: *const c_char,
-        val: *const c_char,
-    ) -> c_int;
-    pub fn ublksrv_json_write_target_long_info(
-        jbuf: *mut c_char,
-        len: c_int,
-        name: *const c_char,
-        val: c_long,
-    ) -> c_int;
-    pub fn ublksrv_json_write_target_ulong_info(
-        jbuf: *mut c_char,
-        len: c_int,
-        name: *const c_char,
-        val: c_ulong,
-    ) -> c_int;
-    pub fn ublksrv_json_dump(jbuf: *const c_char);
-    pub fn ublksrv_json_read_target_base_info(
-        jbuf: *const c_char,
-        tgt: *mut ublksrv_tgt_base_json,
-    ) -> c_int;
-    pub fn ublksrv_json_write_target_base_info(
-        jbuf: *mut c_char,
-        len: c_int,
-        tgt: *const ublksrv_tgt_base_json,
-    ) -> c_int;
-    pub fn ublksrv_json_read_params(p: *mut cmd::ublk_params, jbuf: *const c_char) -> c_int;
-    pub fn ublksrv_json_write_params(
-        p: *const cmd::ublk_params,
-        jbuf: *mut c_char,
-        len: c_int,
-    ) -> c_int;
-    pub fn ublksrv_json_dump_params(jbuf: *const c_char) -> c_int;
-    pub fn ublksrv_json_get_length(jbuf: *const c_char) -> c_int;
+/// Ring of `q`, the target queues its own IO there too
+#[cfg(feature = "libublksrv-1")]
+pub unsafe fn ublksrv_queue_get_ring(q: *const ublksrv_queue) -> *mut iouring::io_uring {
+    (*q).ring_ptr
 }
 
-pub unsafe fn ublksrv_queue_get_data(q: *const ublksrv_queue) -> *mut c_void {
-    (*q).private_data
+/// Sets up the fixed buffer table of the ring of `q`, one entry per tag,
+/// for the zero copy IO of `ublksrv_queue_zc_rw()`. To be called from the
+/// target's queue setup.
+pub unsafe fn ublksrv_queue_register_io_bufs(q: *const ublksrv_queue) -> c_int {
+    iouring::io_uring_register_buffers_sparse(ublksrv_queue_get_ring(q), (*q).q_depth as c_uint)
 }
 
-extern "C" {
-    pub fn ublksrv_queue_init(
-        dev: *mut ublksrv_dev,
-        q_id: c_ushort,
-        nr_extra_ios: c_int,
-        queue_data: *mut c_void,
-    ) -> *mut ublksrv_queue;
-    pub fn ublksrv_queue_deinit(q: *mut ublksrv_queue);
-    pub fn ublksrv_queue_handled_event(q: *mut ublksrv_queue) -> c_int;
-    pub fn ublksrv_queue_send_event(q: *mut ublksrv_queue) -> c_int;
-    pub fn ublksrv_get_queue(dev: *const ublksrv_dev, q_id: c_int) -> *mut ublksrv_queue;
-    pub fn ublksrv_process_io(q: *mut ublksrv_queue) -> c_int;
-    pub fn ublksrv_complete_io(q: *mut ublksrv_queue, tag: c_uint, res: c_int) -> c_int;
-    pub fn ublksrv_register_tgt_type(type_: *mut ublksrv_tgt_type) -> c_int;
-    pub fn ublksrv_unregister_tgt_type(type_: *mut ublksrv_tgt_type);
-    pub fn ublksrv_for_each_tgt_type(
-        handle_tgt_type: Option<
-            unsafe extern "C" fn(idx: c_uint, type_: *const ublksrv_tgt_type, data: *mut c_void),
-        >,
-        data: *mut c_void,
+unsafe fn prep_io_buf_cmd(
+    sqe: *mut iouring::io_uring_sqe,
+    cmd_op: u32,
+    q_id: u16,
+    tag: u16,
+    buf_index: u16,
+) {
+    // The char device is the fixed file 0 of libublksrv's queue rings
+    let mut s = Sqe::uring_cmd(0, cmd_op, 0);
+    s.flags = iouring::IOSQE_FIXED_FILE;
+    let io_cmd = cmd::ublksrv_io_cmd {
+        q_id,
+        tag,
+        result: 0,
+        addr: buf_index as __u64,
+    };
+    std::ptr::copy_nonoverlapping(
+        &io_cmd as *const cmd::ublksrv_io_cmd as *const u8,
+        s.cmd.as_mut_ptr(),
+        std::mem::size_of::<cmd::ublksrv_io_cmd>(),
     );
-    pub fn ublksrv_find_tgt_type(name: *const c_char) -> *const ublksrv_tgt_type;
-    pub fn ublksrv_apply_oom_protection();
+    std::ptr::write(sqe as *mut Sqe, s);
+}
+
+/// Prepares `sqe` to register the buffer of request `tag` of queue `q_id`
+/// at `buf_index` of the ring's fixed buffer table, with
+/// `UBLK_U_IO_REGISTER_IO_BUF`.
+pub unsafe fn ublksrv_prep_register_io_buf(
+    sqe: *mut iouring::io_uring_sqe,
+    q_id: u16,
+    tag: u16,
+    buf_index: u16,
+) {
+    prep_io_buf_cmd(sqe, cmd::UBLK_U_IO_REGISTER_IO_BUF, q_id, tag, buf_index);
 }
 
-/* --------------------------------------------*/
+/// Same as `ublksrv_prep_register_io_buf()`, to unregister it
+pub unsafe fn ublksrv_prep_unregister_io_buf(
+    sqe: *mut iouring::io_uring_sqe,
+    q_id: u16,
+    tag: u16,
+    buf_index: u16,
+) {
+    prep_io_buf_cmd(sqe, cmd::UBLK_U_IO_UNREGISTER_IO_BUF, q_id, tag, buf_index);
+}
+
+/// Prepares `sqe` for the READ or WRITE request `tag` of `q`, on its buffer
+/// registered at `buf_index`, against the target's fixed file `fd_index`
+/// (its index in `tgt.fds`). Completes with
+/// `build_user_data(tag, op, tgt_data, 1)`.
+///
+/// This is the whole IO with `UBLK_F_AUTO_BUF_REG`, where the driver
+/// registered the buffer already. Returns `-EINVAL` for other requests.
+pub unsafe fn ublksrv_prep_rw_fixed(
+    sqe: *mut iouring::io_uring_sqe,
+    q: *const ublksrv_queue,
+    tag: c_int,
+    fd_index: c_int,
+    buf_index: u16,
+    tgt_data: c_uint,
+) -> c_int {
+    let iod = ublksrv_get_iod(q, tag);
+    let op = cmd::ublksrv_get_op(iod) as u32;
+    let len = (*iod).nr_sectors << 9;
+    let offset = (*iod).start_sector << 9;
+
+    match op {
+        cmd::UBLK_IO_OP_READ => iouring::io_uring_prep_read_fixed(
+            sqe,
+            fd_index,
+            std::ptr::null_mut(),
+            len,
+            offset,
+            buf_index as c_int,
+        ),
+        cmd::UBLK_IO_OP_WRITE => iouring::io_uring_prep_write_fixed(
+            sqe,
+            fd_index,
+            std::ptr::null(),
+            len,
+            offset,
+            buf_index as c_int,
+        ),
+        _ => return -libc::EINVAL,
+    }
+    (*sqe).flags |= iouring::IOSQE_FIXED_FILE;
+    (*sqe).user_data = build_user_data(tag as c_uint, op, tgt_data, 1);
+    0
+}
 
-/* --------------------------------------------*/
+/// Queues the READ or WRITE request `tag` of `q`, without copying its data:
+/// its buffer is registered at index `tag` of the ring's fixed buffer table
+/// (see `ublksrv_queue_register_io_bufs()`), read or written from the
+/// target's fixed file `fd_index`, then unregistered.
+///
+/// The IO only runs once the buffer is registered, and the buffer is
+/// unregistered whatever the result of the IO. The IO completes as in
+/// `ublksrv_prep_rw_fixed()`, the unregistration with
+/// `build_user_data(tag, UBLK_IO_UNREGISTER_IO_BUF, tgt_data, 1)`.
+///
+/// The registration only completes on failure, with
+/// `build_user_data(tag, UBLK_IO_REGISTER_IO_BUF, tgt_data, 1)`: the IO and
+/// the unregistration then complete with `-ECANCELED`, so that there are
+/// three cqes. The target tells them apart by the op of their user_data.
+///
+/// Returns the number of cqes to wait for if the registration succeeds, or
+/// a negative errno, `-EAGAIN` if the ring has no room for the sqes.
+pub unsafe fn ublksrv_queue_zc_rw(
+    q: *const ublksrv_queue,
+    tag: c_int,
+    fd_index: c_int,
+    tgt_data: c_uint,
+) -> c_int {
+    let op = cmd::ublksrv_get_op(ublksrv_get_iod(q, tag)) as u32;
+    if op != cmd::UBLK_IO_OP_READ && op != cmd::UBLK_IO_OP_WRITE {
+        return -libc::EINVAL;
+    }
+
+    let ring = ublksrv_queue_get_ring(q);
+    if iouring::io_uring_sq_space_left(ring) < 3 {
+        return -libc::EAGAIN;
+    }
+
+    let q_id = (*q).q_id as u16;
+    let buf_index = tag as u16;
+    let user_data = |op: u32| build_user_data(tag as c_uint, op, tgt_data, 1);
+
+    let reg = iouring::io_uring_get_sqe(ring);
+    ublksrv_prep_register_io_buf(reg, q_id, tag as u16, buf_index);
+    (*reg).flags |= iouring::IOSQE_CQE_SKIP_SUCCESS | iouring::IOSQE_IO_LINK;
+    (*reg).user_data = user_data(cmd::UBLK_IO_REGISTER_IO_BUF);
+
+    let rw = iouring::io_uring_get_sqe(ring);
+    ublksrv_prep_rw_fixed(rw, q, tag, fd_index, buf_index, tgt_data);
+    (*rw).flags |= iouring::IOSQE_IO_HARDLINK;
+
+    let unreg = iouring::io_uring_get_sqe(ring);
+    ublksrv_prep_unregister_io_buf(unreg, q_id, tag as u16, buf_index);
+    (*unreg).user_data = user_data(cmd::UBLK_IO_UNREGISTER_IO_BUF);
+
+    2
+}
+
+/// Char device fd of `dev`, `/dev/ublkc<dev_id>`
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_get_cdev_fd(dev: *const ublksrv_dev) -> c_int {
+    (*dev).cdev_fd
+}
+
+/// Char device fd of `dev`, `/dev/ublkc<dev_id>`.
+///
+/// libublksrv 1.x keeps it private, but hands it to the target as its
+/// first fd.
+#[cfg(feature = "libublksrv-1")]
+pub unsafe fn ublksrv_get_cdev_fd(dev: *const ublksrv_dev) -> c_int {
+    (*dev).tgt.fds[0]
+}
+
+/// `UBLK_F_USER_COPY` data access through the char device of `dev`, which
+/// has to outlive it.
+pub unsafe fn ublksrv_dev_user_copy<'a>(dev: *const ublksrv_dev) -> cmd::UserCopy<'a> {
+    cmd::UserCopy::new(BorrowedFd::borrow_raw(ublksrv_get_cdev_fd(dev)))
+}
+
+/// Same as libublksrv 1.x's `ublksrv_ctrl_get_dev_info`, so the device
+/// info can be read the same way with both APIs.
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_ctrl_get_dev_info(
+    dev: *const ublksrv_ctrl_dev,
+) -> *const cmd::ublksrv_ctrl_dev_info {
+    &(*dev).dev_info
+}
+
+/// Same as libublksrv 1.x's `ublksrv_get_ctrl_dev`
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_get_ctrl_dev(dev: *const ublksrv_dev) -> *const ublksrv_ctrl_dev {
+    (*dev).ctrl_dev
+}
+
+/// Same as libublksrv 1.x's `ublksrv_ctrl_get_run_dir`
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_ctrl_get_run_dir(dev: *const ublksrv_ctrl_dev) -> *const c_char {
+    (*dev).run_dir
+}
+
+/// Same as libublksrv 1.x's `ublksrv_get_queue_affinity`, valid once
+/// `ublksrv_ctrl_get_affinity` succeeded
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_get_queue_affinity(
+    dev: *const ublksrv_ctrl_dev,
+    qid: c_int,
+) -> *const libc::cpu_set_t {
+    (*dev).queues_cpuset.add(qid as usize)
+}
+
+/// Checks that `dev`, as returned by `ublksrv_ctrl_init`, matches the layout
+/// of `ublksrv_ctrl_dev` this crate was built with.
+///
+/// Returns false if the embedded `io_uring` doesn't look like the one set up
+/// by liburing, or the fields after it are not where they are expected, in
+/// which case the device must not be used.
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_ctrl_dev_layout_ok(dev: *const ublksrv_ctrl_dev) -> bool {
+    iouring::io_uring_layout_ok(&(*dev).ring)
+        && (*dev).ctrl_fd != (*dev).ring.ring_fd
+        && iouring::fd_is_open((*dev).ctrl_fd)
+}
+
+/// Same as `ublksrv_ctrl_dev_layout_ok()`, for a queue returned by
+/// `ublksrv_queue_init`.
+#[cfg(not(feature = "libublksrv-1"))]
+pub unsafe fn ublksrv_queue_layout_ok(q: *const ublksrv_queue) -> bool {
+    iouring::io_uring_layout_ok(&(*q).ring)
+        && !(*q).dev.is_null()
+        && (*q).q_depth > 0
+        && (*q).q_depth as u32 <= cmd::UBLK_MAX_QUEUE_DEPTH
+}
+
+/// Checks that the ring of `q`, as returned by `ublksrv_queue_init`, matches
+/// the `io_uring` layout this crate was built with.
+///
+/// libublksrv 1.x no longer embeds the ring in its public structs, but
+/// targets still submit their IO through `ublksrv_queue::ring_ptr`.
+#[cfg(feature = "libublksrv-1")]
+pub unsafe fn ublksrv_queue_layout_ok(q: *const ublksrv_queue) -> bool {
+    !(*q).ring_ptr.is_null()
+        && iouring::io_uring_layout_ok((*q).ring_ptr)
+        && !(*q).dev.is_null()
+        && (*q).q_depth > 0
+        && (*q).q_depth as u32 <= cmd::UBLK_MAX_QUEUE_DEPTH
+}
@@ -0,0 +1,410 @@
+// SPDX-License-Identifier: MIT
+//! Safe handle of a libublksrv control device.
+
+use super::*;
+use crate::cmd::{ublk_params, ublksrv_ctrl_dev_info};
+use crate::error::{Error, Op, Result};
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::ptr::NonNull;
+#[cfg(not(feature = "libublksrv-1"))]
+use std::time::{Duration, Instant};
+
+/// Control device set up by `ublksrv_ctrl_init`, released with
+/// `ublksrv_ctrl_deinit` when dropped.
+///
+/// Deleting the device from the driver is up to the owner, with
+/// `delete()`, a daemon may want to leave it behind for recovery.
+#[derive(Debug)]
+pub struct CtrlDev {
+    dev: NonNull<ublksrv_ctrl_dev>,
+    /// the strings libublksrv points to, with `with_data()`
+    data: Option<DevData>,
+}
+
+impl CtrlDev {
+    /// Sets up a control device from `data`, nothing is sent to the driver
+    /// yet.
+    ///
+    /// # Safety
+    ///
+    /// libublksrv keeps the `tgt_type`, `tgt_ops`, `tgt_argv` and `run_dir`
+    /// pointers of `data`, they must stay valid as long as the `CtrlDev`.
+    pub unsafe fn new(data: &mut ublksrv_dev_data) -> Result<Self> {
+        match NonNull::new(ublksrv_ctrl_init(data)) {
+            Some(dev) => Ok(CtrlDev { dev, data: None }),
+            // libublksrv doesn't return why, open() or the ring setup failed
+            None => {
+                let err = Error::new(Op::OpenControl, io::Error::last_os_error());
+                match u32::try_from(data.dev_id) {
+                    Ok(dev_id) => Err(err.with_dev(dev_id)),
+                    Err(_) => Err(err),
+                }
+            }
+        }
+    }
+
+    /// Sets up a control device from `data`, which it keeps until it is
+    /// dropped, see `DevDataBuilder`.
+    pub fn with_data(mut data: DevData) -> Result<Self> {
+        let mut dev = unsafe { Self::new(&mut *data.as_mut_ptr())? };
+        dev.data = Some(data);
+        Ok(dev)
+    }
+
+    /// The data the device was set up from, with `with_data()`
+    pub fn data(&self) -> Option<&DevData> {
+        self.data.as_ref()
+    }
+
+    /// Checks the return value of libublksrv's `op` on this device
+    fn check(&self, op: Op, ret: c_int) -> Result<()> {
+        Error::check(op, ret).map_err(|e| e.with_dev(self.dev_id()))?;
+        Ok(())
+    }
+
+    /// The libublksrv device, for the calls not wrapped here. It must not
+    /// be deinitialized.
+    pub fn as_ptr(&self) -> *mut ublksrv_ctrl_dev {
+        self.dev.as_ptr()
+    }
+
+    pub fn dev_id(&self) -> u32 {
+        self.info().dev_id
+    }
+
+    /// The device info, as last sent or read back from the driver
+    pub fn info(&self) -> ublksrv_ctrl_dev_info {
+        unsafe { *ublksrv_ctrl_get_dev_info(self.as_ptr()) }
+    }
+
+    /// Reads the device info from the driver and returns it
+    pub fn refresh_info(&mut self) -> Result<ublksrv_ctrl_dev_info> {
+        self.check(Op::GetInfo, unsafe { ublksrv_ctrl_get_info(self.as_ptr()) })?;
+        Ok(self.info())
+    }
+
+    /// Adds the device to the driver, the id it picked is then in `info()`
+    pub fn add(&mut self) -> Result<()> {
+        let ret = unsafe { ublksrv_ctrl_add_dev(self.as_ptr()) };
+        match self.dev_id() {
+            // the driver picks the id
+            u32::MAX => Error::check(Op::AddDev, ret).map(|_| ()),
+            _ => self.check(Op::AddDev, ret),
+        }
+    }
+
+    /// Error of `op`, a libublksrv call that returned null without telling
+    /// why
+    fn null_err(&self, op: Op) -> Error {
+        Error::new(op, io::Error::last_os_error()).with_dev(self.dev_id())
+    }
+
+    /// Sets up the added device with `ublksrv_dev_init`: opens its char
+    /// device and sets up its target. It has to be released with
+    /// `ublksrv_dev_deinit` before the `CtrlDev` is dropped.
+    pub fn init_dev(&self) -> Result<NonNull<ublksrv_dev>> {
+        // only libublksrv 1.x returns it const
+        let dev: *const ublksrv_dev = unsafe { ublksrv_dev_init(self.as_ptr()) };
+        NonNull::new(dev.cast_mut()).ok_or_else(|| self.null_err(Op::DevInit))
+    }
+
+    /// Sets up queue `q_id` of `dev` with `ublksrv_queue_init`, for the
+    /// calling thread to drive it. It has to be released with
+    /// `ublksrv_queue_deinit` before `dev`.
+    ///
+    /// # Safety
+    ///
+    /// `dev` must come from `init_dev()` and not be released yet.
+    #[cfg(not(feature = "libublksrv-1"))]
+    pub unsafe fn init_queue(
+        &self,
+        dev: NonNull<ublksrv_dev>,
+        q_id: u16,
+        nr_extra_ios: c_int,
+        queue_data: *mut libc::c_void,
+    ) -> Result<NonNull<ublksrv_queue>> {
+        let q = ublksrv_queue_init(dev.as_ptr(), q_id, nr_extra_ios, queue_data);
+        NonNull::new(q).ok_or_else(|| self.null_err(Op::QueueInit).with_queue(q_id))
+    }
+
+    /// Sets up queue `q_id` of `dev` with `ublksrv_queue_init`, for the
+    /// calling thread to drive it. It has to be released with
+    /// `ublksrv_queue_deinit` before `dev`.
+    ///
+    /// # Safety
+    ///
+    /// `dev` must come from `init_dev()` and not be released yet.
+    #[cfg(feature = "libublksrv-1")]
+    pub unsafe fn init_queue(
+        &self,
+        dev: NonNull<ublksrv_dev>,
+        q_id: u16,
+        queue_data: *mut libc::c_void,
+    ) -> Result<NonNull<ublksrv_queue>> {
+        let q = ublksrv_queue_init(dev.as_ptr(), q_id, queue_data);
+        NonNull::new(q.cast_mut()).ok_or_else(|| self.null_err(Op::QueueInit).with_queue(q_id))
+    }
+
+    /// Starts the device, served by the process `daemon_pid`, once all the
+    /// queues fetched their requests.
+    pub fn start(&mut self, daemon_pid: i32) -> Result<()> {
+        self.check(Op::StartDev, unsafe {
+            ublksrv_ctrl_start_dev(self.as_ptr(), daemon_pid)
+        })
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        self.check(Op::StopDev, unsafe { ublksrv_ctrl_stop_dev(self.as_ptr()) })
+    }
+
+    /// Removes the device from the driver
+    pub fn delete(&mut self) -> Result<()> {
+        self.check(Op::DelDev, unsafe { ublksrv_ctrl_del_dev(self.as_ptr()) })
+    }
+
+    pub fn set_params(&mut self, params: &ublk_params) -> Result<()> {
+        let mut params = *params;
+        self.check(Op::SetParams, unsafe {
+            ublksrv_ctrl_set_params(self.as_ptr(), &mut params)
+        })
+    }
+
+    /// Reads the params of the device, trimmed to what libublksrv copied,
+    /// see `ublk_params::from_driver()`
+    pub fn get_params(&mut self) -> Result<ublk_params> {
+        let mut params = ublk_params::new();
+        self.check(Op::GetParams, unsafe {
+            ublksrv_ctrl_get_params(self.as_ptr(), &mut params)
+        })?;
+        Ok(ublk_params::from_driver(&params.to_bytes()))
+    }
+
+    /// Changes the size of the live device to `sectors` sectors of 512
+    /// bytes, with `UBLK_U_CMD_UPDATE_SIZE`, which needs
+    /// `UBLK_F_UPDATE_SIZE` support in the driver.
+    ///
+    /// Before shrinking the device, `may_shrink(old_size, new_size)` is
+    /// asked, in bytes: a target with data in use past the new size
+    /// refuses with false, and it fails with `EBUSY`.
+    ///
+    /// Once the driver took it, the size is stored in `tgt.dev_size` of
+    /// `dev` and, with `jbuf`, in the device's json: its `dev_sectors`
+    /// param and its target's `dev_size`. The json is then written back to
+    /// `<run_dir>/<dev_id>.json` if the daemon keeps it there. `jbuf` holds
+    /// the nul terminated json, the rest of it is room for the json to
+    /// grow.
+    ///
+    /// # Safety
+    ///
+    /// `dev` must be the device set up from this control device by
+    /// `ublksrv_dev_init`, and not released yet.
+    pub unsafe fn update_size<F>(
+        &mut self,
+        dev: *mut ublksrv_dev,
+        sectors: u64,
+        jbuf: Option<&mut [u8]>,
+        may_shrink: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(u64, u64) -> bool,
+    {
+        let info = self.info();
+        let update_err = |errno: i32| Error::from_ret(Op::UpdateSize, -errno).with_dev(info.dev_id);
+        let new_size = sectors
+            .checked_mul(512)
+            .ok_or_else(|| update_err(libc::EINVAL))?;
+        if new_size == 0 {
+            return Err(update_err(libc::EINVAL));
+        }
+
+        let old_size = (*dev).tgt.dev_size;
+        if new_size < old_size && !may_shrink(old_size, new_size) {
+            return Err(update_err(libc::EBUSY));
+        }
+
+        ctrl::update_size(info.dev_id, info.flags, sectors)?;
+        (*dev).tgt.dev_size = new_size;
+
+        match jbuf {
+            Some(jbuf) => self.update_json_size(jbuf, sectors),
+            None => Ok(()),
+        }
+    }
+
+    /// Stores the size set by `update_size()` in the json `jbuf`, and
+    /// writes it back to the run dir
+    fn update_json_size(&self, jbuf: &mut [u8], sectors: u64) -> Result<()> {
+        let dev_id = self.dev_id();
+        let json_err = |op, ret| Error::from_ret(op, ret).with_dev(dev_id);
+
+        let json_len = match jbuf.iter().position(|&b| b == 0) {
+            Some(len) => len,
+            None => return Err(json_err(Op::JsonReadParams, -libc::EINVAL)),
+        };
+        let jbuf_len = c_int::try_from(jbuf.len()).unwrap_or(c_int::MAX);
+        let jbuf_ptr = jbuf.as_mut_ptr() as *mut c_char;
+
+        let mut params = cmd::ublk_params::default();
+        if unsafe { ublksrv_json_read_params(&mut params, jbuf_ptr) } >= 0 {
+            params.basic.dev_sectors = sectors;
+            let ret = unsafe { ublksrv_json_write_params(&params, jbuf_ptr, jbuf_len) };
+            if ret < 0 {
+                return Err(json_err(Op::JsonWriteParams, ret));
+            }
+        }
+
+        let mut base = ublksrv_tgt_base_json::default();
+        if unsafe { ublksrv_json_read_target_base_info(jbuf_ptr, &mut base) } >= 0 {
+            base.dev_size = sectors << 9;
+            let ret = unsafe { ublksrv_json_write_target_base_info(jbuf_ptr, jbuf_len, &base) };
+            if ret < 0 {
+                return Err(json_err(Op::JsonWriteParams, ret));
+            }
+        }
+
+        let run_dir = unsafe { ublksrv_ctrl_get_run_dir(self.as_ptr()) };
+        if run_dir.is_null() {
+            return Ok(());
+        }
+        let run_dir = unsafe { CStr::from_ptr(run_dir) };
+        let path = Path::new(OsStr::from_bytes(run_dir.to_bytes())).join(format!("{dev_id}.json"));
+        if !path.exists() {
+            return Ok(());
+        }
+
+        // the json may have grown
+        let json_len = jbuf.iter().position(|&b| b == 0).unwrap_or(json_len);
+        std::fs::write(&path, &jbuf[..json_len])
+            .map_err(|e| Error::new(Op::JsonWriteParams, e).with_dev(dev_id))
+    }
+
+    /// Pauses the IO of the live device and resumes it, e.g. to take a
+    /// snapshot of its backing store, with `UBLK_U_CMD_QUIESCE_DEV`.
+    ///
+    /// The device must have been added with `UBLK_F_QUIESCE` and
+    /// `UBLK_F_USER_RECOVERY`, otherwise it fails with `EOPNOTSUPP`. The
+    /// driver waits up to `timeout` for the inflight requests, then aborts
+    /// the commands of the queues, whose `ublksrv_process_io` fails from
+    /// then on. Once none of `queues` has a command inflight any more and
+    /// the device is quiesced, `paused` is called, it fails with
+    /// `ETIMEDOUT` if that takes longer than `timeout`.
+    ///
+    /// `paused` has to release the queues and the device
+    /// (`ublksrv_queue_deinit`, `ublksrv_dev_deinit`), the driver doesn't
+    /// resume a device whose char device is still open. The device is then
+    /// resumed as the daemon `daemon_pid` like in `recover()`, with `setup`.
+    /// An error of `paused` leaves the device quiesced.
+    ///
+    /// # Safety
+    ///
+    /// `queues` must be queues of this device set up by
+    /// `ublksrv_queue_init`, their threads must not release them before
+    /// `paused` is called.
+    #[cfg(not(feature = "libublksrv-1"))]
+    pub unsafe fn quiesce<P, F>(
+        &mut self,
+        queues: &[*const ublksrv_queue],
+        timeout: Duration,
+        daemon_pid: i32,
+        paused: P,
+        setup: F,
+    ) -> Result<()>
+    where
+        P: FnOnce(&mut Self) -> Result<()>,
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        let info = self.info();
+        let quiesce_err =
+            |errno: i32| Error::from_ret(Op::QuiesceDev, -errno).with_dev(info.dev_id);
+        let needed = (cmd::UBLK_F_QUIESCE | cmd::UBLK_F_USER_RECOVERY) as u64;
+        if info.flags & needed != needed {
+            return Err(quiesce_err(libc::EOPNOTSUPP));
+        }
+
+        let timeout_ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+        ctrl::quiesce_dev(info.dev_id, info.flags, timeout_ms)?;
+
+        // The queue threads see their commands aborted and leave
+        // ublksrv_process_io once they have nothing inflight
+        let deadline = Instant::now() + timeout;
+        loop {
+            let drained = queues
+                .iter()
+                .all(|&q| std::ptr::addr_of!((*q).cmd_inflight).read_volatile() == 0);
+            let quiesced = self.refresh_info()?.state == cmd::UBLK_S_DEV_QUIESCED as u16;
+            if drained && quiesced {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(quiesce_err(libc::ETIMEDOUT));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        paused(self)?;
+        self.recover(daemon_pid, setup)
+    }
+
+    /// Reattaches the calling process, as the new daemon `daemon_pid`, to
+    /// the device after its previous daemon died, or after `quiesce()`.
+    ///
+    /// The device must have been added with `UBLK_F_USER_RECOVERY` (or
+    /// `UBLK_F_USER_RECOVERY_REISSUE`), otherwise it fails with
+    /// `EOPNOTSUPP`. A previous daemon other than this process must be
+    /// gone, otherwise it fails with `EBUSY`.
+    ///
+    /// `setup` is called once the driver accepted the recovery, it has to
+    /// set up the device and every queue (`init_dev()`, `init_queue()`)
+    /// and start driving them with
+    /// `ublksrv_process_io`, since the driver only ends the recovery after
+    /// all the queues fetched their requests. An error of `setup` aborts
+    /// the recovery, the device stays quiesced and a later call can try
+    /// again.
+    pub fn recover<F>(&mut self, daemon_pid: i32, setup: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        let info = self.refresh_info()?;
+        let recovery = (cmd::UBLK_F_USER_RECOVERY | cmd::UBLK_F_USER_RECOVERY_REISSUE) as u64;
+        if info.flags & recovery == 0 {
+            return Err(Error::from_ret(Op::StartRecovery, -libc::EOPNOTSUPP).with_dev(info.dev_id));
+        }
+
+        // The old daemon has to be gone before the driver lets a new one in
+        let old_pid = info.ublksrv_pid;
+        if old_pid > 0
+            && old_pid as u32 != std::process::id()
+            && unsafe { libc::kill(old_pid, 0) } == 0
+        {
+            return Err(Error::from_ret(Op::StartRecovery, -libc::EBUSY).with_dev(info.dev_id));
+        }
+
+        self.check(Op::StartRecovery, unsafe {
+            ublksrv_ctrl_start_recovery(self.as_ptr())
+        })?;
+        setup(self)?;
+        self.check(Op::EndRecovery, unsafe {
+            ublksrv_ctrl_end_recovery(self.as_ptr(), daemon_pid)
+        })
+    }
+
+    /// Reads the CPUs the driver assigned to each queue, indexed by queue id
+    pub fn affinity(&mut self) -> Result<Vec<libc::cpu_set_t>> {
+        self.check(Op::GetAffinity, unsafe {
+            ublksrv_ctrl_get_affinity(self.as_ptr())
+        })?;
+
+        let nr_queues = self.info().nr_hw_queues as c_int;
+        Ok((0..nr_queues)
+            .map(|q_id| unsafe { *ublksrv_get_queue_affinity(self.as_ptr(), q_id) })
+            .collect())
+    }
+}
+
+impl Drop for CtrlDev {
+    fn drop(&mut self) {
+        unsafe { ublksrv_ctrl_deinit(self.as_ptr()) }
+    }
+}
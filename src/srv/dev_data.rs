@@ -0,0 +1,276 @@
+// SPDX-License-Identifier: MIT
+//! Checked construction of `ublksrv_dev_data`.
+
+use super::*;
+use crate::error::{Error, Op, Result};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+fn invalid(msg: String) -> Error {
+    Error::new(
+        Op::DevData,
+        io::Error::new(io::ErrorKind::InvalidInput, msg),
+    )
+}
+
+fn c_string(what: &str, s: &[u8]) -> Result<CString> {
+    CString::new(s).map_err(|_| invalid(format!("{what} contains a nul byte")))
+}
+
+/// `ublksrv_dev_data` along with the strings it points to, see
+/// `DevDataBuilder`.
+///
+/// The pointers stay valid when a `DevData` is moved. libublksrv keeps them
+/// in the control device, so a `DevData` has to outlive it, which
+/// `CtrlDev::with_data()` takes care of.
+#[derive(Debug)]
+pub struct DevData {
+    data: ublksrv_dev_data,
+    _tgt_type: CString,
+    _run_dir: Option<CString>,
+    _args: Vec<CString>,
+    /// `tgt_argv`, null terminated like `main()`'s
+    _argv: Vec<*mut c_char>,
+}
+
+impl DevData {
+    pub fn builder(tgt_type: &str) -> DevDataBuilder {
+        DevDataBuilder::new(tgt_type)
+    }
+
+    pub fn as_raw(&self) -> &ublksrv_dev_data {
+        &self.data
+    }
+
+    /// For `ublksrv_ctrl_init`, which only reads it
+    pub fn as_mut_ptr(&mut self) -> *mut ublksrv_dev_data {
+        &mut self.data
+    }
+}
+
+/// Builder of `DevData`, starting from libublksrv's defaults: any free
+/// device id, `DEF_NR_HW_QUEUES` queues of `DEF_QD` requests and
+/// `DEF_BUF_SIZE` bytes IO buffers.
+///
+/// `build()` checks the values against the limits of libublksrv
+/// (`MAX_NR_HW_QUEUES`, `MAX_QD`, `MAX_BUF_SIZE`) before any of them reach
+/// it.
+#[derive(Debug, Clone)]
+pub struct DevDataBuilder {
+    dev_id: Option<u32>,
+    nr_hw_queues: u32,
+    queue_depth: u32,
+    max_io_buf_bytes: u32,
+    tgt_type: String,
+    tgt_ops: Option<&'static ublksrv_tgt_type>,
+    tgt_args: Vec<String>,
+    run_dir: Option<PathBuf>,
+    flags: u64,
+    ublksrv_flags: u64,
+}
+
+impl DevDataBuilder {
+    pub fn new(tgt_type: &str) -> Self {
+        DevDataBuilder {
+            dev_id: None,
+            nr_hw_queues: DEF_NR_HW_QUEUES,
+            queue_depth: DEF_QD,
+            max_io_buf_bytes: DEF_BUF_SIZE,
+            tgt_type: tgt_type.to_owned(),
+            tgt_ops: None,
+            tgt_args: Vec::new(),
+            run_dir: None,
+            flags: 0,
+            ublksrv_flags: 0,
+        }
+    }
+
+    /// Id of the device, the driver picks a free one if it isn't set
+    pub fn dev_id(mut self, dev_id: u32) -> Self {
+        self.dev_id = Some(dev_id);
+        self
+    }
+
+    pub fn nr_hw_queues(mut self, nr_hw_queues: u32) -> Self {
+        self.nr_hw_queues = nr_hw_queues;
+        self
+    }
+
+    pub fn queue_depth(mut self, queue_depth: u32) -> Self {
+        self.queue_depth = queue_depth;
+        self
+    }
+
+    pub fn max_io_buf_bytes(mut self, max_io_buf_bytes: u32) -> Self {
+        self.max_io_buf_bytes = max_io_buf_bytes;
+        self
+    }
+
+    pub fn tgt_ops(mut self, tgt_ops: &'static ublksrv_tgt_type) -> Self {
+        self.tgt_ops = Some(tgt_ops);
+        self
+    }
+
+    /// Arguments of the target, passed to its `init_tgt` as `argc`/`argv`
+    pub fn tgt_args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tgt_args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Directory of the device's pid and json files, libublksrv's
+    /// `UBLKSRV_RUN_DIR` if it isn't set
+    pub fn run_dir(mut self, run_dir: impl Into<PathBuf>) -> Self {
+        self.run_dir = Some(run_dir.into());
+        self
+    }
+
+    /// `UBLK_F_*` flags of the device
+    pub fn flags(mut self, flags: u64) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// `UBLKSRV_F_*` flags, for libublksrv itself
+    pub fn ublksrv_flags(mut self, ublksrv_flags: u64) -> Self {
+        self.ublksrv_flags = ublksrv_flags;
+        self
+    }
+
+    pub fn build(self) -> Result<DevData> {
+        let check = |what: &str, val: u32, max: u32| {
+            if val == 0 || val > max {
+                return Err(invalid(format!("{what} {val} is out of 1..={max}")));
+            }
+            Ok(())
+        };
+        check("nr_hw_queues", self.nr_hw_queues, MAX_NR_HW_QUEUES)?;
+        check("queue_depth", self.queue_depth, MAX_QD)?;
+        check("max_io_buf_bytes", self.max_io_buf_bytes, MAX_BUF_SIZE)?;
+        if self.max_io_buf_bytes & 511 != 0 {
+            return Err(invalid(format!(
+                "max_io_buf_bytes {} isn't a multiple of 512",
+                self.max_io_buf_bytes
+            )));
+        }
+
+        let dev_id = match self.dev_id {
+            None => -1,
+            Some(id) => {
+                c_int::try_from(id).map_err(|_| invalid(format!("dev_id {id} is out of range")))?
+            }
+        };
+        if self.tgt_type.is_empty() {
+            return Err(invalid("tgt_type is empty".to_owned()));
+        }
+        let tgt_type = c_string("tgt_type", self.tgt_type.as_bytes())?;
+        let run_dir = self
+            .run_dir
+            .as_ref()
+            .map(|dir| c_string("run_dir", dir.as_os_str().as_bytes()))
+            .transpose()?;
+        let args = self
+            .tgt_args
+            .iter()
+            .map(|arg| c_string("target argument", arg.as_bytes()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut argv: Vec<*mut c_char> = args.iter().map(|a| a.as_ptr() as *mut c_char).collect();
+        argv.push(std::ptr::null_mut());
+
+        let data = ublksrv_dev_data {
+            dev_id,
+            max_io_buf_bytes: self.max_io_buf_bytes,
+            nr_hw_queues: self.nr_hw_queues as _,
+            queue_depth: self.queue_depth as _,
+            tgt_type: tgt_type.as_ptr(),
+            tgt_ops: self
+                .tgt_ops
+                .map_or(std::ptr::null(), |ops| ops as *const ublksrv_tgt_type),
+            tgt_argc: args.len() as c_int,
+            tgt_argv: argv.as_mut_ptr(),
+            run_dir: run_dir.as_ref().map_or(std::ptr::null(), |d| d.as_ptr()),
+            flags: self.flags as _,
+            ublksrv_flags: self.ublksrv_flags as _,
+            ..Default::default()
+        };
+
+        Ok(DevData {
+            data,
+            _tgt_type: tgt_type,
+            _run_dir: run_dir,
+            _args: args,
+            _argv: argv,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_limits() {
+        let valid: [fn(DevDataBuilder) -> DevDataBuilder; 6] = [
+            |b| b,
+            |b| b.nr_hw_queues(MAX_NR_HW_QUEUES),
+            |b| b.queue_depth(1),
+            |b| b.queue_depth(MAX_QD),
+            |b| b.max_io_buf_bytes(512),
+            |b| b.max_io_buf_bytes(MAX_BUF_SIZE).dev_id(c_int::MAX as u32),
+        ];
+        for (i, set) in valid.into_iter().enumerate() {
+            assert!(set(DevData::builder("null")).build().is_ok(), "case {i}");
+        }
+
+        let invalid: [fn(DevDataBuilder) -> DevDataBuilder; 9] = [
+            |b| b.nr_hw_queues(0),
+            |b| b.nr_hw_queues(MAX_NR_HW_QUEUES + 1),
+            |b| b.queue_depth(0),
+            |b| b.queue_depth(MAX_QD + 1),
+            |b| b.max_io_buf_bytes(0),
+            |b| b.max_io_buf_bytes(MAX_BUF_SIZE + 512),
+            |b| b.max_io_buf_bytes(4097),
+            |b| b.dev_id(c_int::MAX as u32 + 1),
+            |b| b.tgt_args(["a\0b"]),
+        ];
+        for (i, set) in invalid.into_iter().enumerate() {
+            let err = set(DevData::builder("null")).build().unwrap_err();
+            assert_eq!(err.op(), Op::DevData, "case {i}");
+            assert_eq!(
+                err.io_error().kind(),
+                io::ErrorKind::InvalidInput,
+                "case {i}"
+            );
+        }
+
+        assert!(DevData::builder("").build().is_err());
+    }
+
+    #[test]
+    fn builder_fills_dev_data() {
+        let data = DevData::builder("loop")
+            .dev_id(3)
+            .nr_hw_queues(2)
+            .queue_depth(64)
+            .tgt_args(["-f", "/tmp/img"])
+            .build()
+            .unwrap();
+        let raw = data.as_raw();
+        assert_eq!(raw.dev_id, 3);
+        assert_eq!(raw.nr_hw_queues, 2);
+        assert_eq!(raw.queue_depth, 64);
+        assert_eq!(raw.max_io_buf_bytes, DEF_BUF_SIZE);
+        assert_eq!(raw.tgt_argc, 2);
+        assert!(unsafe { (*raw.tgt_argv.add(2)).is_null() });
+        assert!(raw.run_dir.is_null());
+
+        let data = DevData::builder("null").build().unwrap();
+        assert_eq!(data.as_raw().dev_id, -1);
+    }
+}
@@ -0,0 +1,359 @@
+// SPDX-License-Identifier: MIT
+#![allow(dead_code)]
+#![allow(non_camel_case_types)]
+#![allow(clippy::missing_safety_doc)] // FIXME
+
+//! libublksrv 1.x API, where the control device, device and queue internals
+//! are private to the library and reached through accessors.
+
+use crate::iouring;
+use crate::{cmd, d};
+use libc::{c_char, c_int, c_long, c_uint, c_ulong, c_ulonglong, c_ushort, c_void};
+
+pub const MAX_NR_HW_QUEUES: u32 = 32;
+pub const MAX_QD: u32 = 1024;
+pub const MAX_BUF_SIZE: u32 = 1024 << 10;
+
+pub const DEF_NR_HW_QUEUES: u32 = 1;
+pub const DEF_QD: u32 = 256;
+pub const DEF_BUF_SIZE: u32 = 512 << 10;
+
+pub const UBLKSRV_SHM_DIR: &[u8; 8] = b"ublksrv\0";
+pub const UBLKSRV_SHM_SIZE: u32 = 1024;
+
+// stored in ublksrv_ctrl_dev_info->ublksrv_flags
+
+/// HAS_IO_DAEMON means io handler has its own daemon context which isn't
+/// same with control command context, so shared memory communication is
+/// required between control task and io daemon
+pub const UBLKSRV_F_HAS_IO_DAEMON: u32 = 1;
+
+/// target may not use io_uring for handling io, so eventfd is required
+/// for wakeup io command io_uring context
+pub const UBLKSRV_F_NEED_EVENTFD: u32 = 2;
+
+// Opaque types
+// TODO: Replace with a extern type once RFC 1861 becomes stable
+// #![feature(extern_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ublksrv_aio_ctx {
+    _private: [u8; 0],
+}
+
+/// Only usable through the `ublksrv_ctrl_*` functions
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ublksrv_ctrl_dev {
+    _private: [u8; 0],
+}
+
+/// Generic data for creating one ublk device
+///
+/// Target specific data is handled by ->init_tgt
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ublksrv_dev_data {
+    pub dev_id: c_int,
+    pub max_io_buf_bytes: c_uint,
+    pub nr_hw_queues: c_ushort,
+    pub queue_depth: c_ushort,
+    pub tgt_type: *const c_char,
+    pub tgt_ops: *const ublksrv_tgt_type,
+    pub tgt_argc: c_int,
+    pub tgt_argv: *mut *mut c_char,
+    pub run_dir: *const c_char,
+    pub flags: c_ulong,
+    pub ublksrv_flags: c_ulong,
+    pub reserved: [c_ulong; 7],
+}
+d!(ublksrv_dev_data);
+
+/// The public part of a queue, the rest is private to libublksrv
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ublksrv_queue {
+    pub q_id: c_int,
+    pub q_depth: c_int,
+    /// ring for submit io command to ublk driver, target io can be
+    /// submitted via this ring too
+    pub ring_ptr: *mut iouring::io_uring,
+    pub dev: *const ublksrv_dev,
+    pub private_data: *mut c_void,
+}
+d!(ublksrv_queue);
+
+/// Passed to the target for each IO, replaces indexing `ublksrv_queue::ios`
+/// by tag
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ublk_io_data {
+    pub tag: c_int,
+    pub pad: c_uint,
+    pub iod: *const cmd::ublksrv_io_desc,
+    pub private_data: *mut c_void,
+}
+d!(ublk_io_data);
+
+pub const UBLKSRV_TGT_MAX_FDS: u32 = 32;
+
+// enum
+/// evaluate communication cost, ublksrv_null vs /dev/nullb0
+pub const UBLKSRV_TGT_TYPE_NULL: c_uint = 0;
+/// ublksrv_loop vs. /dev/loop
+pub const UBLKSRV_TGT_TYPE_LOOP: c_uint = 1;
+pub const UBLKSRV_TGT_TYPE_MAX: c_uint = 256;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ublksrv_tgt_info {
+    pub dev_size: c_ulonglong,
+    /// at most in-flight ios
+    pub tgt_ring_depth: c_uint,
+    pub nr_fds: c_uint,
+    pub fds: [c_int; UBLKSRV_TGT_MAX_FDS as usize],
+    pub tgt_data: *mut c_void,
+    /// extra IO slots for each queue, target code can reserve some
+    /// slots for handling internal IO, such as meta data IO
+    pub extra_ios: c_uint,
+    /// max workers of the io_uring io-wq, bounded and unbounded
+    pub iowq_max_workers: [c_uint; 2],
+    pub reserved: [c_ulong; 4],
+    pub ops: *const ublksrv_tgt_type,
+}
+d!(ublksrv_tgt_info);
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ublksrv_tgt_type {
+    /// One IO request comes from /dev/ublkbN, so notify target code
+    /// for handling the IO. Inside target code, the IO can be handled
+    /// with our io_uring too, if this is true, ->tgt_io_done callback
+    /// has to be implemented. Otherwise, target can implement
+    /// ->handle_event() for processing io completion there.
+    pub handle_io_async:
+        Option<unsafe extern "C" fn(q: *const ublksrv_queue, io: *const ublk_io_data) -> c_int>,
+
+    /// target io is handled by our io_uring, and once the target io
+    /// is completed, this callback is called
+    pub tgt_io_done: Option<
+        unsafe extern "C" fn(
+            q: *const ublksrv_queue,
+            io: *const ublk_io_data,
+            cqe: *const iouring::io_uring_cqe,
+        ),
+    >,
+
+    /// Someone has written to our eventfd, so let target handle the
+    /// event, most of times, it is for handling io completion by
+    /// calling ublksrv_complete_io() which has to be run in ubq_daemon context.
+    pub handle_event: Option<unsafe extern "C" fn(q: *const ublksrv_queue)>,
+
+    /// One typical use case is to flush meta data, which is usually done
+    /// in background, see `ublksrv_tgt_info::extra_ios`.
+    ///
+    /// @nr_queued_io: count of queued IOs in ublksrv_reap_events_uring of this time
+    pub handle_io_background:
+        Option<unsafe extern "C" fn(q: *const ublksrv_queue, nr_queued_io: c_int)>,
+
+    /// show target specific command line for adding new device
+    ///
+    /// Be careful: this callback is the only one which is not run from
+    /// ublk device daemon task context.
+    pub usage_for_add: Option<unsafe extern "C" fn()>,
+
+    /// initialize this new target, argc/argv includes target specific
+    /// command line parameters
+    pub init_tgt: Option<
+        unsafe extern "C" fn(
+            dev: *mut ublksrv_dev,
+            type_: c_int,
+            argc: c_int,
+            argv: *mut *mut c_char,
+        ) -> c_int,
+    >,
+
+    /// deinitialize this target
+    pub deinit_tgt: Option<unsafe extern "C" fn(dev: *const ublksrv_dev)>,
+
+    pub alloc_io_buf: Option<
+        unsafe extern "C" fn(q: *const ublksrv_queue, tag: c_int, size: c_int) -> *mut c_void,
+    >,
+    pub free_io_buf:
+        Option<unsafe extern "C" fn(q: *const ublksrv_queue, buf: *mut c_void, tag: c_int)>,
+
+    /// Called when the queue is about to become idle (`enter == true`),
+    /// and when it leaves the idle state
+    pub idle_fn: Option<unsafe extern "C" fn(q: *const ublksrv_queue, enter: bool)>,
+
+    pub type_: c_int,
+
+    /// flags required for ublk driver
+    pub ublk_flags: c_uint,
+    /// flags required for ublksrv
+    pub ublksrv_flags: c_uint,
+    pub pad: c_uint,
+    pub name: *const c_char,
+
+    /// recover a previous target, for a device in recovery
+    /// (`ublksrv_ctrl_prep_recovery`), instead of ->init_tgt
+    pub recovery_tgt: Option<unsafe extern "C" fn(dev: *mut ublksrv_dev, type_: c_int) -> c_int>,
+
+    /// per-queue target data, stored in `ublksrv_queue::private_data`
+    pub init_queue: Option<
+        unsafe extern "C" fn(q: *const ublksrv_queue, queue_data_ptr: *mut *mut c_void) -> c_int,
+    >,
+    pub deinit_queue: Option<unsafe extern "C" fn(q: *const ublksrv_queue)>,
+
+    pub reserved: [c_ulong; 5],
+}
+d!(ublksrv_tgt_type);
+
+/// The public part of a device, the rest is private to libublksrv
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ublksrv_dev {
+    pub tgt: ublksrv_tgt_info,
+}
+d!(ublksrv_dev);
+
+extern "C" {
+    pub fn ublksrv_ctrl_deinit(dev: *mut ublksrv_ctrl_dev);
+    pub fn ublksrv_ctrl_init(data: *mut ublksrv_dev_data) -> *mut ublksrv_ctrl_dev;
+    pub fn ublksrv_ctrl_get_affinity(ctrl_dev: *mut ublksrv_ctrl_dev) -> c_int;
+    pub fn ublksrv_ctrl_add_dev(dev: *mut ublksrv_ctrl_dev) -> c_int;
+    pub fn ublksrv_ctrl_del_dev(dev: *mut ublksrv_ctrl_dev) -> c_int;
+    pub fn ublksrv_ctrl_get_info(dev: *mut ublksrv_ctrl_dev) -> c_int;
+    pub fn ublksrv_ctrl_stop_dev(dev: *mut ublksrv_ctrl_dev) -> c_int;
+    pub fn ublksrv_ctrl_dump(dev: *mut ublksrv_ctrl_dev, buf: *const c_char);
+    pub fn ublksrv_ctrl_start_dev(ctrl_dev: *mut ublksrv_ctrl_dev, daemon_pid: c_int) -> c_int;
+    pub fn ublksrv_ctrl_set_params(
+        dev: *mut ublksrv_ctrl_dev,
+        params: *mut cmd::ublk_params,
+    ) -> c_int;
+    pub fn ublksrv_ctrl_get_params(
+        dev: *mut ublksrv_ctrl_dev,
+        params: *mut cmd::ublk_params,
+    ) -> c_int;
+    pub fn ublksrv_ctrl_start_recovery(dev: *mut ublksrv_ctrl_dev) -> c_int;
+    pub fn ublksrv_ctrl_end_recovery(dev: *mut ublksrv_ctrl_dev, daemon_pid: c_int) -> c_int;
+
+    pub fn ublksrv_ctrl_get_dev_info(
+        dev: *const ublksrv_ctrl_dev,
+    ) -> *const cmd::ublksrv_ctrl_dev_info;
+    pub fn ublksrv_ctrl_get_run_dir(dev: *const ublksrv_ctrl_dev) -> *const c_char;
+    pub fn ublksrv_ctrl_prep_recovery(
+        dev: *mut ublksrv_ctrl_dev,
+        tgt_type: *const c_char,
+        tgt_ops: *const ublksrv_tgt_type,
+        recovery_jbuf: *const c_char,
+    );
+    pub fn ublksrv_ctrl_get_recovery_jbuf(dev: *const ublksrv_ctrl_dev) -> *const c_char;
+    pub fn ublksrv_get_queue_affinity(
+        dev: *const ublksrv_ctrl_dev,
+        qid: c_int,
+    ) -> *const libc::cpu_set_t;
+
+    pub fn ublksrv_dev_init(ctrl_dev: *const ublksrv_ctrl_dev) -> *const ublksrv_dev;
+    pub fn ublksrv_dev_deinit(dev: *const ublksrv_dev);
+    pub fn ublksrv_get_ctrl_dev(dev: *const ublksrv_dev) -> *const ublksrv_ctrl_dev;
+    pub fn ublksrv_get_pidfile_fd(dev: *const ublksrv_dev) -> c_int;
+    pub fn ublksrv_dev_set_cq_depth(dev: *mut ublksrv_dev, cq_depth: c_int);
+    pub fn ublksrv_dev_get_cq_depth(dev: *mut ublksrv_dev) -> c_int;
+}
+
+/// target json has to include the following key/value
+pub const UBLKSRV_TGT_NAME_MAX_LEN: u32 = 32;
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ublksrv_tgt_base_json {
+    pub name: [c_char; UBLKSRV_TGT_NAME_MAX_LEN as usize],
+    pub type_: c_int,
+    pub dev_size: c_ulonglong,
+}
+
+extern "C" {
+    pub fn ublksrv_json_write_dev_info(
+        dev: *const ublksrv_ctrl_dev,
+        buf: *mut c_char,
+        len: c_int,
+    ) -> c_int;
+    pub fn ublksrv_json_read_dev_info(
+        json_buf: *const c_char,
+        info: *mut cmd::ublksrv_ctrl_dev_info,
+    ) -> c_int;
+    pub fn ublksrv_json_write_queue_info(
+        dev: *const ublksrv_ctrl_dev,
+        jbuf: *mut c_char,
+        len: c_int,
+        qid: c_int,
+        ubq_daemon_tid: c_int,
+    ) -> c_int;
+    pub fn ublksrv_json_read_queue_info(
+        jbuf: *const c_char,
+        qid: c_int,
+        tid: *mut c_uint,
+        affinity_buf: *mut c_char,
+        len: c_int,
+    ) -> c_int;
+    pub fn ublksrv_json_read_target_info(
+        jbuf: *const c_char,
+        tgt_buf: *mut c_char,
+        len: c_int,
+    ) -> c_int;
+    pub fn ublksrv_json_write_target_str_info(
+        jbuf: *mut c_char,
+        len: c_int,
+        name: *const c_char,
+        val: *const c_char,
+    ) -> c_int;
+    pub fn ublksrv_json_write_target_long_info(
+        jbuf: *mut c_char,
+        len: c_int,
+        name: *const c_char,
+        val: c_long,
+    ) -> c_int;
+    pub fn ublksrv_json_write_target_ulong_info(
+        jbuf: *mut c_char,
+        len: c_int,
+        name: *const c_char,
+        val: c_ulong,
+    ) -> c_int;
+    pub fn ublksrv_json_dump(jbuf: *const c_char);
+    pub fn ublksrv_json_read_target_base_info(
+        jbuf: *const c_char,
+        tgt: *mut ublksrv_tgt_base_json,
+    ) -> c_int;
+    pub fn ublksrv_json_write_target_base_info(
+        jbuf: *mut c_char,
+        len: c_int,
+        tgt: *const ublksrv_tgt_base_json,
+    ) -> c_int;
+    pub fn ublksrv_json_read_params(p: *mut cmd::ublk_params, jbuf: *const c_char) -> c_int;
+    pub fn ublksrv_json_write_params(
+        p: *const cmd::ublk_params,
+        jbuf: *mut c_char,
+        len: c_int,
+    ) -> c_int;
+    pub fn ublksrv_json_dump_params(jbuf: *const c_char) -> c_int;
+    pub fn ublksrv_json_get_length(jbuf: *const c_char) -> c_int;
+}
+
+extern "C" {
+    pub fn ublksrv_queue_init(
+        dev: *const ublksrv_dev,
+        q_id: c_ushort,
+        queue_data: *mut c_void,
+    ) -> *const ublksrv_queue;
+    pub fn ublksrv_queue_deinit(q: *const ublksrv_queue);
+    pub fn ublksrv_queue_handled_event(q: *const ublksrv_queue) -> c_int;
+    pub fn ublksrv_queue_send_event(q: *const ublksrv_queue) -> c_int;
+    pub fn ublksrv_get_queue(dev: *const ublksrv_dev, q_id: c_int) -> *const ublksrv_queue;
+    pub fn ublksrv_queue_get_data(q: *const ublksrv_queue) -> *mut c_void;
+    pub fn ublksrv_queue_get_io_data(q: *const ublksrv_queue, tag: c_int) -> *const ublk_io_data;
+    pub fn ublksrv_io_private_data(q: *const ublksrv_queue, tag: c_int) -> *mut c_void;
+    pub fn ublksrv_queue_unconsumed_cqes(q: *const ublksrv_queue) -> c_int;
+    pub fn ublksrv_process_io(q: *const ublksrv_queue) -> c_int;
+    pub fn ublksrv_complete_io(q: *const ublksrv_queue, tag: c_uint, res: c_int) -> c_int;
+    pub fn ublksrv_apply_oom_protection();
+}
@@ -0,0 +1,391 @@
+// SPDX-License-Identifier: MIT
+//! Minimal io_uring, set up with the raw syscalls, for sending ublk's
+//! `IORING_OP_URING_CMD`s without liburing.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub(crate) const IORING_OP_READ_FIXED: u8 = 4;
+pub(crate) const IORING_OP_WRITE_FIXED: u8 = 5;
+pub(crate) const IORING_OP_URING_CMD: u8 = 46;
+
+pub(crate) const IORING_SETUP_SQE128: u32 = 1 << 10;
+
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES: i64 = 0x10000000;
+
+/// Offset of the command payload of `IORING_OP_URING_CMD` in the sqe
+const SQE_CMD_OFFSET: usize = 48;
+pub(crate) const SQE_SIZE: usize = 64;
+const CQE_SIZE: usize = 16;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct io_sqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct io_cqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct io_uring_params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: io_sqring_offsets,
+    cq_off: io_cqring_offsets,
+}
+
+/// `struct io_uring_sqe`, without the unions, the first 64 bytes of an
+/// SQE128 one
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct Sqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    /// `off`, or `cmd_op` in its first 4 bytes for `IORING_OP_URING_CMD`
+    pub off: u64,
+    pub addr: u64,
+    pub len: u32,
+    pub op_flags: u32,
+    pub user_data: u64,
+    pub buf_index: u16,
+    pub personality: u16,
+    pub file_index: u32,
+    /// `addr3`, or the start of the command payload
+    pub cmd: [u8; 16],
+}
+
+impl Sqe {
+    pub fn uring_cmd(fd: i32, cmd_op: u32, user_data: u64) -> Self {
+        let mut sqe = Sqe {
+            opcode: IORING_OP_URING_CMD,
+            fd,
+            user_data,
+            ..Default::default()
+        };
+        // cmd_op comes first in memory whatever the endianness
+        unsafe { ptr::write(ptr::addr_of_mut!(sqe.off) as *mut u32, cmd_op) };
+        sqe
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct Cqe {
+    pub user_data: u64,
+    pub res: i32,
+    pub flags: u32,
+}
+
+pub(crate) struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    /// Shared read/write mapping of `len` bytes of `fd` at `offset`
+    pub fn new(fd: RawFd, offset: i64, len: usize) -> io::Result<Self> {
+        Self::map(
+            fd,
+            offset,
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+        )
+    }
+
+    /// Same as `new()`, read only
+    pub fn new_ro(fd: RawFd, offset: i64, len: usize) -> io::Result<Self> {
+        Self::map(fd, offset, len, libc::PROT_READ, libc::MAP_SHARED)
+    }
+
+    /// Zeroed, page aligned, memory
+    pub fn anon(len: usize) -> io::Result<Self> {
+        Self::map(
+            -1,
+            0,
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        )
+    }
+
+    fn map(fd: RawFd, offset: i64, len: usize, prot: i32, flags: i32) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                flags | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.add(offset as usize) as *mut T
+    }
+}
+
+// Only hands out raw pointers, accessing the memory is up to the user
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+pub(crate) struct Ring {
+    sq_ring: Mmap,
+    /// `None` with `IORING_FEAT_SINGLE_MMAP`, the cq ring is in `sq_ring`
+    cq_ring: Option<Mmap>,
+    sqes: Mmap,
+    params: io_uring_params,
+    sqe_size: usize,
+    // unsubmitted sqes, from the kernel's sq tail
+    sq_pending: u32,
+    fd: OwnedFd,
+}
+
+impl Ring {
+    /// Ring of `entries` sqes, `flags` are `IORING_SETUP_*`
+    pub fn new(entries: u32, flags: u32) -> io::Result<Self> {
+        let mut params = io_uring_params {
+            flags,
+            ..Default::default()
+        };
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut io_uring_params,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(ret as RawFd) };
+
+        let sqe_size = if flags & IORING_SETUP_SQE128 != 0 {
+            2 * SQE_SIZE
+        } else {
+            SQE_SIZE
+        };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * CQE_SIZE;
+
+        let (sq_ring, cq_ring) = if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            let len = sq_len.max(cq_len);
+            (Mmap::new(fd.as_raw_fd(), IORING_OFF_SQ_RING, len)?, None)
+        } else {
+            (
+                Mmap::new(fd.as_raw_fd(), IORING_OFF_SQ_RING, sq_len)?,
+                Some(Mmap::new(fd.as_raw_fd(), IORING_OFF_CQ_RING, cq_len)?),
+            )
+        };
+        let sqes = Mmap::new(
+            fd.as_raw_fd(),
+            IORING_OFF_SQES,
+            params.sq_entries as usize * sqe_size,
+        )?;
+
+        Ok(Ring {
+            sq_ring,
+            cq_ring,
+            sqes,
+            params,
+            sqe_size,
+            sq_pending: 0,
+            fd,
+        })
+    }
+
+    fn cq(&self) -> &Mmap {
+        self.cq_ring.as_ref().unwrap_or(&self.sq_ring)
+    }
+
+    fn sq_tail(&self) -> &AtomicU32 {
+        unsafe { &*self.sq_ring.at(self.params.sq_off.tail) }
+    }
+
+    fn sq_head(&self) -> &AtomicU32 {
+        unsafe { &*self.sq_ring.at(self.params.sq_off.head) }
+    }
+
+    fn cq_head(&self) -> &AtomicU32 {
+        unsafe { &*self.cq().at(self.params.cq_off.head) }
+    }
+
+    fn cq_tail(&self) -> &AtomicU32 {
+        unsafe { &*self.cq().at(self.params.cq_off.tail) }
+    }
+
+    /// Queues an `IORING_OP_URING_CMD` of `fd` with `cmd` as its payload,
+    /// which has to fit in the sqe.
+    pub fn prep_uring_cmd(
+        &mut self,
+        fd: RawFd,
+        cmd_op: u32,
+        cmd: &[u8],
+        user_data: u64,
+    ) -> io::Result<()> {
+        if SQE_CMD_OFFSET + cmd.len() > self.sqe_size {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let tail = self
+            .sq_tail()
+            .load(Ordering::Relaxed)
+            .wrapping_add(self.sq_pending);
+        let head = self.sq_head().load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= self.params.sq_entries {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+
+        let mask = unsafe { *self.sq_ring.at::<u32>(self.params.sq_off.ring_mask) };
+        let idx = tail & mask;
+        unsafe {
+            let sqe = self.sqes.ptr.add(idx as usize * self.sqe_size);
+            ptr::write_bytes(sqe, 0, self.sqe_size);
+            ptr::write(sqe as *mut Sqe, Sqe::uring_cmd(fd, cmd_op, user_data));
+            ptr::copy_nonoverlapping(cmd.as_ptr(), sqe.add(SQE_CMD_OFFSET), cmd.len());
+
+            let array: *mut u32 = self.sq_ring.at(self.params.sq_off.array);
+            *array.add(idx as usize) = idx;
+        }
+        self.sq_pending += 1;
+        Ok(())
+    }
+
+    /// Number of sqes queued and not submitted yet
+    pub fn pending(&self) -> u32 {
+        self.sq_pending
+    }
+
+    /// Submits the queued sqes and waits for `wait_nr` completions.
+    ///
+    /// Returns the number of sqes submitted. The wait may end early, e.g.
+    /// on a signal once the sqes are submitted, so fewer completions may be
+    /// there. On error none of the sqes was submitted, they are dropped.
+    pub fn submit_and_wait(&mut self, wait_nr: u32) -> io::Result<usize> {
+        let to_submit = self.sq_pending;
+        let tail = self.sq_tail().load(Ordering::Relaxed);
+        self.sq_tail()
+            .store(tail.wrapping_add(to_submit), Ordering::Release);
+        self.sq_pending = 0;
+
+        let flags = if wait_nr > 0 {
+            IORING_ENTER_GETEVENTS
+        } else {
+            0
+        };
+        loop {
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    to_submit,
+                    wait_nr,
+                    flags,
+                    ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+            if ret >= 0 {
+                return Ok(ret as usize);
+            }
+
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            // The sqes the kernel didn't take mustn't go with the next ones.
+            // Without SQPOLL it only reads the sq in io_uring_enter().
+            let head = self.sq_head().load(Ordering::Acquire);
+            self.sq_tail().store(head, Ordering::Release);
+            return match head.wrapping_sub(tail) {
+                0 => Err(err),
+                submitted => Ok(submitted as usize),
+            };
+        }
+    }
+
+    /// Takes the next completion, if any
+    pub fn pop_cqe(&mut self) -> Option<Cqe> {
+        let head = self.cq_head().load(Ordering::Relaxed);
+        if head == self.cq_tail().load(Ordering::Acquire) {
+            return None;
+        }
+
+        let cq = self.cq();
+        let cqe = unsafe {
+            let mask = *cq.at::<u32>(self.params.cq_off.ring_mask);
+            let cqes: *const Cqe = cq.at(self.params.cq_off.cqes);
+            *cqes.add((head & mask) as usize)
+        };
+        self.cq_head()
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(cqe)
+    }
+}
+
+impl AsRawFd for Ring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
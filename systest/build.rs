@@ -0,0 +1,611 @@
+// SPDX-License-Identifier: MIT
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+/// A struct declared by ublk-sys, with its fields.
+///
+/// Fields are written as `name`, or `rust_name=c_name` when the Rust field
+/// stands for an anonymous C member (e.g. anonymous unions). A trailing `[]`
+/// marks a flexible array member, its size is not checked.
+struct Struct {
+    module: &'static str,
+    name: &'static str,
+    fields: &'static [&'static str],
+}
+
+const STRUCTS: &[Struct] = &[
+    // cmd
+    Struct {
+        module: "cmd",
+        name: "ublksrv_ctrl_cmd",
+        fields: &[
+            "dev_id",
+            "queue_id",
+            "len",
+            "addr",
+            "data",
+            "dev_path_len",
+            "pad",
+            "reserved",
+        ],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublksrv_ctrl_dev_info",
+        fields: &[
+            "nr_hw_queues",
+            "queue_depth",
+            "state",
+            "pad0",
+            "max_io_buf_bytes",
+            "dev_id",
+            "ublksrv_pid",
+            "pad1",
+            "flags",
+            "ublksrv_flags",
+            "owner_uid",
+            "owner_gid",
+            "reserved1",
+            "reserved2",
+        ],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublksrv_io_desc",
+        fields: &["op_flags", "nr_sectors", "start_sector", "addr"],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublksrv_io_cmd",
+        fields: &["q_id", "tag", "result", "addr"],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublk_param_basic",
+        fields: &[
+            "attrs",
+            "logical_bs_shift",
+            "physical_bs_shift",
+            "io_opt_shift",
+            "io_min_shift",
+            "max_sectors",
+            "chunk_sectors",
+            "dev_sectors",
+            "virt_boundary_mask",
+        ],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublk_param_discard",
+        fields: &[
+            "discard_alignment",
+            "discard_granularity",
+            "max_discard_sectors",
+            "max_write_zeroes_sectors",
+            "max_discard_segments",
+            "reserved0",
+        ],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublk_params",
+        fields: &[
+            "len", "types", "basic", "discard", "devt", "zoned", "dma", "seg",
+        ],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublk_param_devt",
+        fields: &["char_major", "char_minor", "disk_major", "disk_minor"],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublk_param_zoned",
+        fields: &[
+            "max_open_zones",
+            "max_active_zones",
+            "max_zone_append_sectors",
+            "reserved",
+        ],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublk_param_dma_align",
+        fields: &["alignment", "pad"],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublk_param_segment",
+        fields: &[
+            "seg_boundary_mask",
+            "max_segment_size",
+            "max_segments",
+            "pad",
+        ],
+    },
+    Struct {
+        module: "cmd",
+        name: "ublk_auto_buf_reg",
+        fields: &["index", "flags", "reserved0", "reserved1"],
+    },
+    Struct {
+        module: "cmd",
+        name: "blk_zone",
+        fields: &[
+            "start",
+            "len",
+            "wp",
+            "type_=type",
+            "cond",
+            "non_seq",
+            "reset",
+            "resv",
+            "capacity",
+            "reserved",
+        ],
+    },
+    // aio
+    Struct {
+        module: "aio",
+        name: "ublksrv_aio_ctx",
+        fields: &[
+            "submit", "complete", "efd", "flags", "dead", "dev", "ctx_data",
+        ],
+    },
+    Struct {
+        module: "aio",
+        name: "ublksrv_aio",
+        fields: &["io", "union=res", "id", "next", "data[]"],
+    },
+    Struct {
+        module: "aio",
+        name: "ublksrv_aio_list",
+        fields: &["lock", "list"],
+    },
+    Struct {
+        module: "aio",
+        name: "aio_list",
+        fields: &["head", "tail"],
+    },
+    // iouring
+    Struct {
+        module: "iouring",
+        name: "io_uring",
+        fields: &[
+            "sq",
+            "cq",
+            "flags",
+            "ring_fd",
+            "features",
+            "enter_ring_fd",
+            "int_flags",
+            "pad",
+            "pad2",
+        ],
+    },
+    Struct {
+        module: "iouring",
+        name: "io_uring_sq",
+        fields: &[
+            "khead",
+            "ktail",
+            "kring_mask",
+            "kring_entries",
+            "kflags",
+            "kdropped",
+            "array",
+            "sqes",
+            "sqe_head",
+            "sqe_tail",
+            "ring_sz",
+            "ring_ptr",
+        ],
+    },
+    Struct {
+        module: "iouring",
+        name: "io_uring_cq",
+        fields: &[
+            "khead",
+            "ktail",
+            "kring_mask",
+            "kring_entries",
+            "kflags",
+            "koverflow",
+            "cqes",
+            "ring_sz",
+            "ring_ptr",
+        ],
+    },
+    Struct {
+        module: "iouring",
+        name: "io_uring_sqe",
+        fields: &[
+            "opcode",
+            "flags",
+            "ioprio",
+            "fd",
+            "u1=off",
+            "u2=addr",
+            "len",
+            "u3=rw_flags",
+            "user_data",
+            "u4=buf_index",
+            "personality",
+            "u5=splice_fd_in",
+            "addr3",
+        ],
+    },
+    Struct {
+        module: "iouring",
+        name: "io_uring_cqe",
+        fields: &["user_data", "res", "flags", "big_cqe[]"],
+    },
+];
+
+/// `srv` structs of the legacy libublksrv API
+const SRV_STRUCTS: &[Struct] = &[
+    Struct {
+        module: "srv",
+        name: "ublksrv_dev_data",
+        fields: &[
+            "dev_id",
+            "max_io_buf_bytes",
+            "nr_hw_queues",
+            "queue_depth",
+            "tgt_type",
+            "tgt_ops",
+            "tgt_argc",
+            "tgt_argv",
+            "run_dir",
+            "flags",
+            "ublksrv_flags",
+            "reserved",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_ctrl_dev",
+        fields: &[
+            "ring",
+            "ctrl_fd",
+            "bs_shift",
+            "dev_info",
+            "tgt_type",
+            "tgt_ops",
+            "run_dir",
+            "tgt_argc",
+            "tgt_argv",
+            "queues_cpuset",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublk_io",
+        fields: &["buf_addr", "flags", "union=result", "tgt_io_cqe", "io_data"],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_queue",
+        fields: &[
+            "q_id",
+            "q_depth",
+            "private_data",
+            "io_cmd_buf",
+            "io_buf",
+            "cmd_inflight",
+            "tgt_io_inflight",
+            "state",
+            "efd",
+            "tgt_ops",
+            "ring",
+            "dev",
+            "tid",
+            "nr_ctxs",
+            "ctxs",
+            "ios[]",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_tgt_info",
+        fields: &[
+            "dev_size",
+            "tgt_ring_depth",
+            "nr_fds",
+            "fds",
+            "tgt_data",
+            "ops",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_tgt_type",
+        fields: &[
+            "handle_io_async",
+            "tgt_io_done",
+            "handle_event",
+            "handle_io_background",
+            "usage_for_add",
+            "init_tgt",
+            "deinit_tgt",
+            "alloc_io_buf",
+            "free_io_buf",
+            "type_=type",
+            "ublk_flags",
+            "ublksrv_flags",
+            "extra_ios",
+            "name",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_dev",
+        fields: &[
+            "tgt",
+            "__queues",
+            "io_buf_start",
+            "thread",
+            "cdev_fd",
+            "pid_file_fd",
+            "ctrl_dev",
+            "target_data",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_tgt_base_json",
+        fields: &["name", "type_=type", "dev_size"],
+    },
+];
+
+/// `srv` structs of the libublksrv 1.x API, where the rest is opaque
+const SRV_STRUCTS_V1: &[Struct] = &[
+    Struct {
+        module: "srv",
+        name: "ublksrv_dev_data",
+        fields: &[
+            "dev_id",
+            "max_io_buf_bytes",
+            "nr_hw_queues",
+            "queue_depth",
+            "tgt_type",
+            "tgt_ops",
+            "tgt_argc",
+            "tgt_argv",
+            "run_dir",
+            "flags",
+            "ublksrv_flags",
+            "reserved",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_queue",
+        fields: &["q_id", "q_depth", "ring_ptr", "dev", "private_data"],
+    },
+    Struct {
+        module: "srv",
+        name: "ublk_io_data",
+        fields: &["tag", "pad", "iod", "private_data"],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_tgt_info",
+        fields: &[
+            "dev_size",
+            "tgt_ring_depth",
+            "nr_fds",
+            "fds",
+            "tgt_data",
+            "extra_ios",
+            "iowq_max_workers",
+            "reserved",
+            "ops",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_tgt_type",
+        fields: &[
+            "handle_io_async",
+            "tgt_io_done",
+            "handle_event",
+            "handle_io_background",
+            "usage_for_add",
+            "init_tgt",
+            "deinit_tgt",
+            "alloc_io_buf",
+            "free_io_buf",
+            "idle_fn",
+            "type_=type",
+            "ublk_flags",
+            "ublksrv_flags",
+            "pad",
+            "name",
+            "recovery_tgt",
+            "init_queue",
+            "deinit_queue",
+            "reserved",
+        ],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_dev",
+        fields: &["tgt"],
+    },
+    Struct {
+        module: "srv",
+        name: "ublksrv_tgt_base_json",
+        fields: &["name", "type_=type", "dev_size"],
+    },
+];
+
+fn structs() -> impl Iterator<Item = &'static Struct> {
+    let srv = if cfg!(feature = "libublksrv-1") {
+        SRV_STRUCTS_V1
+    } else {
+        SRV_STRUCTS
+    };
+    STRUCTS.iter().chain(srv)
+}
+
+/// Fields added to `io_uring_sq` and `io_uring_cq` by liburing 2.3
+const LIBURING_2_3_FIELDS: &[&str] = &["ring_mask", "ring_entries"];
+
+struct Field<'a> {
+    rust: &'a str,
+    c: &'a str,
+    flexible: bool,
+}
+
+fn parse_field(spec: &str) -> Field<'_> {
+    let (spec, flexible) = match spec.strip_suffix("[]") {
+        Some(spec) => (spec, true),
+        None => (spec, false),
+    };
+    let (rust, c) = spec.split_once('=').unwrap_or((spec, spec));
+    Field { rust, c, flexible }
+}
+
+fn fields(s: &Struct) -> Vec<Field<'static>> {
+    let mut fields: Vec<_> = s.fields.iter().map(|f| parse_field(f)).collect();
+
+    // Set by ublk-sys' build script when it selects the 2.3 layout
+    if env::var_os("DEP_UBLKSRV_LIBURING_2_3").is_some()
+        && (s.name == "io_uring_sq" || s.name == "io_uring_cq")
+    {
+        fields.extend(LIBURING_2_3_FIELDS.iter().map(|f| parse_field(f)));
+    }
+    fields
+}
+
+fn generate_c() -> String {
+    let mut c = String::from(
+        "#include <stddef.h>\n\
+         #include <linux/blkzoned.h>\n\
+         #include <liburing.h>\n\
+         #include <ublksrv.h>\n\
+         #include <ublksrv_aio.h>\n\n",
+    );
+
+    for s in structs() {
+        let name = s.name;
+        writeln!(
+            c,
+            "size_t ublk_systest_size_{name}(void) {{ return sizeof(struct {name}); }}\n\
+             size_t ublk_systest_align_{name}(void) {{ return _Alignof(struct {name}); }}"
+        )
+        .unwrap();
+
+        for field in fields(s) {
+            let (rust, cname) = (field.rust, field.c);
+            writeln!(
+                c,
+                "size_t ublk_systest_offset_{name}__{rust}(void) \
+                 {{ return offsetof(struct {name}, {cname}); }}"
+            )
+            .unwrap();
+            if !field.flexible {
+                writeln!(
+                    c,
+                    "size_t ublk_systest_fsize_{name}__{rust}(void) \
+                     {{ return sizeof(((struct {name} *)0)->{cname}); }}"
+                )
+                .unwrap();
+            }
+        }
+        c.push('\n');
+    }
+    c
+}
+
+fn generate_rust() -> String {
+    let mut rs = String::new();
+
+    for s in structs() {
+        let (module, name) = (s.module, s.name);
+        let fields = fields(s);
+
+        writeln!(
+            rs,
+            "#[test]\nfn {name}() {{\n    type T = ublk_sys::{module}::{name};"
+        )
+        .unwrap();
+
+        writeln!(
+            rs,
+            "    extern \"C\" {{\n        \
+             fn ublk_systest_size_{name}() -> usize;\n        \
+             fn ublk_systest_align_{name}() -> usize;"
+        )
+        .unwrap();
+        for field in &fields {
+            let rust = field.rust;
+            writeln!(
+                rs,
+                "        fn ublk_systest_offset_{name}__{rust}() -> usize;"
+            )
+            .unwrap();
+            if !field.flexible {
+                writeln!(
+                    rs,
+                    "        fn ublk_systest_fsize_{name}__{rust}() -> usize;"
+                )
+                .unwrap();
+            }
+        }
+        rs.push_str("    }\n");
+
+        writeln!(
+            rs,
+            "    assert_eq!(mem::size_of::<T>(), unsafe {{ ublk_systest_size_{name}() }}, \
+             \"size of {name}\");\n    \
+             assert_eq!(mem::align_of::<T>(), unsafe {{ ublk_systest_align_{name}() }}, \
+             \"alignment of {name}\");"
+        )
+        .unwrap();
+
+        for field in &fields {
+            let (rust, cname) = (field.rust, field.c);
+            writeln!(
+                rs,
+                "    assert_eq!(mem::offset_of!(T, {rust}), \
+                 unsafe {{ ublk_systest_offset_{name}__{rust}() }}, \
+                 \"offset of {name}::{cname}\");"
+            )
+            .unwrap();
+            if !field.flexible {
+                writeln!(
+                    rs,
+                    "    assert_eq!(field_size(|s: *const T| unsafe {{ std::ptr::addr_of!((*s).{rust}) }}), \
+                     unsafe {{ ublk_systest_fsize_{name}__{rust}() }}, \
+                     \"size of {name}::{cname}\");"
+                )
+                .unwrap();
+            }
+        }
+        rs.push_str("}\n\n");
+    }
+    rs
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let probe = out_dir.join("probe.c");
+    fs::write(&probe, generate_c()).unwrap();
+    fs::write(out_dir.join("layout.rs"), generate_rust()).unwrap();
+
+    // Exported by ublk-sys' build script, see `links` in its manifest
+    let include = env::var_os("DEP_UBLKSRV_INCLUDE").unwrap_or_default();
+
+    cc::Build::new()
+        .file(&probe)
+        .includes(env::split_paths(&include))
+        .define("_GNU_SOURCE", None)
+        .warnings(false)
+        .compile("ublk_systest");
+}
@@ -0,0 +1,6 @@
+// SPDX-License-Identifier: MIT
+//! ABI layout tests for ublk-sys, see `tests/layout.rs`.
+//!
+//! The build script compiles a C probe against the installed (or vendored)
+//! headers, reporting `sizeof`, `_Alignof` and `offsetof` of every struct
+//! and field that ublk-sys declares by hand.
@@ -0,0 +1,11 @@
+// SPDX-License-Identifier: MIT
+use std::mem;
+// the C probe is linked through the library crate
+use ublk_sys_test as _;
+
+/// Size of the field returned by `f`, without needing an instance of `T`
+fn field_size<T, F>(_f: fn(*const T) -> *const F) -> usize {
+    mem::size_of::<F>()
+}
+
+include!(concat!(env!("OUT_DIR"), "/layout.rs"));